@@ -17,6 +17,12 @@ void Midi314::processPotEvent(byte channel, byte fn, byte value) {
         case POT_PAN:        midi314.controlChange(channel, MIDI_CC_PAN, value);            break;
         case POT_REVERB:     midi314.controlChange(channel, MIDI_CC_REVERB, value);         break;
         case POT_CHORUS:     midi314.controlChange(channel, MIDI_CC_CHORUS, value);         break;
+        case POT_EFFECT_PARAM_1: midi314.controlChange(channel, MIDI_CC_CUSTOM_EFFECT_PARAM_1, value); break;
+        case POT_EFFECT_PARAM_2: midi314.controlChange(channel, MIDI_CC_CUSTOM_EFFECT_PARAM_2, value); break;
+        case POT_EFFECT_MIX:     midi314.controlChange(channel, MIDI_CC_CUSTOM_EFFECT_MIX, value);     break;
+        // The range of the potentiometer is divided between the targets and the effects.
+        case POT_EFFECT_TARGET:  midi314.controlChange(channel, MIDI_CC_CUSTOM_EFFECT_TARGET, (int)value * EFFECT_TARGETS / 128); break;
+        case POT_EFFECT_SELECT:  midi314.controlChange(channel, MIDI_CC_CUSTOM_EFFECT_SELECT, (int)value * EFFECTS / 128);        break;
     }
 }
 
@@ -22,6 +22,12 @@ enum {
     MIDI_CC_POLY_MODE_ON           = 127,
 
     // Looper events (non-standard).
+    MIDI_CC_CUSTOM_LOOP_PROGRAM    = 14,
+    MIDI_CC_CUSTOM_SAVE_SCENE      = 15,
+    MIDI_CC_CUSTOM_EFFECT_PARAM_1  = 16,
+    MIDI_CC_CUSTOM_EFFECT_PARAM_2  = 17,
+    MIDI_CC_CUSTOM_EFFECT_MIX      = 18,
+    MIDI_CC_CUSTOM_EFFECT_TARGET   = 19, // loop index, or 9 for the master output
     MIDI_CC_CUSTOM_RECORD          = 20,
     MIDI_CC_CUSTOM_PLAY            = 21,
     MIDI_CC_CUSTOM_MUTE            = 22,
@@ -30,9 +36,26 @@ enum {
     MIDI_CC_CUSTOM_ALL             = 25,
     MIDI_CC_CUSTOM_SET_MIN_PITCH   = 26,
     MIDI_CC_CUSTOM_SET_MIN_PROGRAM = 27,
+    MIDI_CC_CUSTOM_UNDO            = 29,
+    MIDI_CC_CUSTOM_REDO            = 30,
+    MIDI_CC_CUSTOM_MONITORING      = 62, // always, never, while recording, while idle
+    MIDI_CC_CUSTOM_EFFECT_SELECT   = 80, // filter, delay, reverb, bitcrusher, tremolo
+    MIDI_CC_CUSTOM_REVERSE         = 85,
+    MIDI_CC_CUSTOM_HALF_SPEED      = 86,
+    MIDI_CC_CUSTOM_DOUBLE_SPEED    = 87,
+    MIDI_CC_CUSTOM_RECALL_SCENE    = 88,
+    MIDI_CC_CUSTOM_TEMPO_UP        = 89,
+    MIDI_CC_CUSTOM_TEMPO_DOWN      = 90,
+    MIDI_CC_CUSTOM_LOOP_GAIN       = 102, // + loop index
+    MIDI_CC_CUSTOM_LOOP_PAN        = 111, // + loop index
 
     // UI events (non-standard)
     MIDI_CC_CUSTOM_PERCUSSION      = 28,
+
+    // Looper feedback events (non-standard).
+    MIDI_CC_CUSTOM_LOOPER_STATE    = 3,
+    MIDI_CC_CUSTOM_LOOP_POSITION   = 9,
+    MIDI_CC_CUSTOM_LOOP_STATE      = 52, // + loop index
 };
 
 // The MIDI channel of the instrument.
@@ -56,8 +79,17 @@ enum {
     POT_PAN,
     POT_REVERB,
     POT_CHORUS,
+    POT_EFFECT_PARAM_1,
+    POT_EFFECT_PARAM_2,
+    POT_EFFECT_MIX,
+    POT_EFFECT_TARGET,
+    POT_EFFECT_SELECT,
 };
 
+// The number of effect targets (9 loops and the master output) and of effects in the looper.
+#define EFFECT_TARGETS 10
+#define EFFECTS 5
+
 /* --------------------------------------------------------------------------- *
  * Midi314 class
  * --------------------------------------------------------------------------- */
@@ -131,17 +131,35 @@ enum {
     LOOP_MUTED
 };
 
-// The current state of each loop.
+// The current state of each loop, as published by the looper.
 static byte loopState[LOOPS];
 
+// The maximum time to wait for the looper to publish the state of a loop after a command, in milliseconds.
+// Commands that do not change the state of the loop are not confirmed.
+#define LOOP_PENDING_TIME_MS 250
+
+// Whether a command has been sent for each loop and the looper has not published its state yet,
+// and the time of the command.
+static bool loopPending[LOOPS];
+static unsigned long loopCommandTimeMs[LOOPS];
+
 // The current MIDI channel.
 static byte midiChannel;
 
 // The current mono/polyphonic mode.
 static bool monoMode;
 
+// Uncomment to control the effects of the looper with the potentiometers
+// instead of the synthesizer.
+// #define EFFECT_POTS
+
+#ifdef EFFECT_POTS
+// The assignment of potentiometers that controls the effects of the looper.
+static const byte potFn[] = {POT_EFFECT_TARGET, POT_EFFECT_SELECT, POT_EFFECT_PARAM_1, POT_EFFECT_PARAM_2, POT_EFFECT_MIX};
+#else
 // The default assignment of potentiometers.
 static const byte potFn[] = {POT_VOLUME, POT_PAN, POT_REVERB, POT_CHORUS, POT_MODULATION};
+#endif
 
 static void scan() {
     // All column output pins are supposed to be high before scanning.
@@ -208,12 +226,28 @@ static inline int getMaxPitch() {
     return pitchOffset + keyNotes[ROWS-2][COLS-1];
 }
 
-static bool isRecording;
+static inline bool isPending(int n) {
+    if (loopPending[n] && millis() - loopCommandTimeMs[n] >= LOOP_PENDING_TIME_MS) {
+        loopPending[n] = false;
+    }
+    return loopPending[n];
+}
+
+// Send a command for a loop, and ignore the next commands for this loop until the looper publishes its state.
+static void sendLoopCommand(byte cc, int n) {
+    loopPending[n] = true;
+    loopCommandTimeMs[n] = millis();
+    midi314.controlChange(DEFAULT_MIDI_CHANNEL, cc, n);
+}
+
+static inline bool isRecording() {
+    return loopState[currentLoop] == LOOP_RECORDING;
+}
 
 static inline void stopRecording() {
-    loopState[currentLoop] = LOOP_PLAYING;
-    isRecording = false;
-    midi314.controlChange(DEFAULT_MIDI_CHANNEL, MIDI_CC_CUSTOM_PLAY, currentLoop);
+    if (!isPending(currentLoop)) {
+        sendLoopCommand(MIDI_CC_CUSTOM_PLAY, currentLoop);
+    }
 }
 
 static inline void updatePitchOffset(int n) {
@@ -238,66 +272,65 @@ static inline void updateMidiProgramOffset(int n) {
 }
 
 static void playSolo(int n) {
-    if (loopState[n] == LOOP_MUTED) {
-        loopState[n] = LOOP_PLAYING;
-    }
-    for (int i = 0; i < LOOPS; i ++) {
-        if (i != n && loopState[i] == LOOP_PLAYING) {
-            loopState[i] = LOOP_MUTED;
-        }
-    }
-    midi314.controlChange(DEFAULT_MIDI_CHANNEL, MIDI_CC_CUSTOM_SOLO, n);
+    sendLoopCommand(MIDI_CC_CUSTOM_SOLO, n);
 }
 
 static void playAllLoops() {
     // Unmute all muted loops.
-    for (int i = 0; i < LOOPS; i ++) {
-        if (loopState[i] == LOOP_MUTED) {
-            loopState[i] = LOOP_PLAYING;
-        }
-    }
     midi314.controlChange(DEFAULT_MIDI_CHANNEL, MIDI_CC_CUSTOM_ALL, 0);
 }
 
+// Send a loop command according to the current state of the loop.
+// The loop states are not changed here: they are updated when the looper publishes them.
+// While a command is pending, the state is not up to date, and the key is ignored.
 static inline void updateLoop(int n) {
+    if (isPending(n)) {
+        return;
+    }
     switch (loopState[n]) {
         case LOOP_EMPTY:
-            if (!DEL_KEY_PRESSED && !isRecording) {
-                loopState[n] = LOOP_RECORDING;
+            if (!DEL_KEY_PRESSED && !isRecording() && !isPending(currentLoop)) {
                 currentLoop = n;
-                isRecording = true;
-                midi314.controlChange(DEFAULT_MIDI_CHANNEL, MIDI_CC_CUSTOM_RECORD, n);
+                sendLoopCommand(MIDI_CC_CUSTOM_RECORD, n);
             }
             break;
         case LOOP_PLAYING:
             if (DEL_KEY_PRESSED) {
-                loopState[n] = LOOP_EMPTY;
-                midi314.controlChange(DEFAULT_MIDI_CHANNEL, MIDI_CC_CUSTOM_DELETE, n);
+                sendLoopCommand(MIDI_CC_CUSTOM_DELETE, n);
             }
             else if (SOLO_KEY_PRESSED) {
                 playSolo(n);
             }
             else {
-                loopState[n] = LOOP_MUTED;
-                midi314.controlChange(DEFAULT_MIDI_CHANNEL, MIDI_CC_CUSTOM_MUTE, n);
+                sendLoopCommand(MIDI_CC_CUSTOM_MUTE, n);
             }
             break;
         case LOOP_MUTED:
             if (DEL_KEY_PRESSED) {
-                loopState[n] = LOOP_EMPTY;
-                midi314.controlChange(DEFAULT_MIDI_CHANNEL, MIDI_CC_CUSTOM_DELETE, n);
+                sendLoopCommand(MIDI_CC_CUSTOM_DELETE, n);
             }
             else if (SOLO_KEY_PRESSED) {
                 playSolo(n);
             }
             else {
-                loopState[n] = LOOP_PLAYING;
-                midi314.controlChange(DEFAULT_MIDI_CHANNEL, MIDI_CC_CUSTOM_PLAY, n);
+                sendLoopCommand(MIDI_CC_CUSTOM_PLAY, n);
             }
             break;
     }
 }
 
+// Update the loop states from the events published by the looper.
+static void processMidiInput() {
+    midiEventPacket_t mep;
+    while ((mep = MidiUSB.read()).header != 0) {
+        byte cc = mep.byte2;
+        if (mep.header == 0x0B && cc >= MIDI_CC_CUSTOM_LOOP_STATE && cc < MIDI_CC_CUSTOM_LOOP_STATE + LOOPS && mep.byte3 <= LOOP_MUTED) {
+            loopState[cc - MIDI_CC_CUSTOM_LOOP_STATE] = mep.byte3;
+            loopPending[cc - MIDI_CC_CUSTOM_LOOP_STATE] = false;
+        }
+    }
+}
+
 static void forcePotEvents() {
     for (int p = 0; p < POTS; p ++) {
         midi314.pushEvent(POT_EVENT, p);
@@ -351,7 +384,17 @@ static void processFunctionKey(int r, int c) {
             }
             break;
         case KEY_TEMPO:
-            // TODO Not implemented.
+            switch (arg) {
+                case KEY_DOWN:
+                    midi314.controlChange(DEFAULT_MIDI_CHANNEL, MIDI_CC_CUSTOM_TEMPO_DOWN, 1);
+                    break;
+                case KEY_UP:
+                    midi314.controlChange(DEFAULT_MIDI_CHANNEL, MIDI_CC_CUSTOM_TEMPO_UP, 1);
+                    break;
+                default:
+                    // TODO Tap tempo not implemented.
+                    break;
+            }
             break;
         case KEY_LOOP:
             switch (arg) {
@@ -391,7 +434,7 @@ static void processEvents() {
 
             case PRESS_EVENT:
                 if (keyFn[evt.row][evt.col] == KEY_FN) {
-                    if (isRecording) {
+                    if (isRecording()) {
                         stopRecording();
                     }
                 }
@@ -428,13 +471,13 @@ static void reset() {
     midiProgramOffset = 0;
     midiProgram = 0;
     currentLoop = 0;
-    isRecording = false;
     monoMode = false;
 
     // FIXME send MIDI events to ensure this state is consistent with synth
 
     for (int l = 0; l < LOOPS; l ++) {
         loopState[l] = LOOP_EMPTY;
+        loopPending[l] = false;
     }
 
     Serial.println("Ready");
@@ -463,6 +506,7 @@ void setup() {
 }
 
 void loop() {
+    processMidiInput();
     processEvents();
     midi314.flushMidi();
 
@@ -4,7 +4,7 @@ extern crate midi314;
 extern crate pcd8544;
 
 use std::{thread, time};
-use midi314::{Keyboard, LoopManager, LoopState};
+use midi314::{Keyboard, LoopManager, LoopSpeed, LoopState, LooperState};
 use pcd8544::{PCD8544, Orientation};
 
 const LCD_RST    : u64 = 24;
@@ -12,20 +12,52 @@ const LCD_DC     : u64 = 25;
 const LCD_SPI    : &'static str = "/dev/spidev0.0";
 const LCD_ORIENT : Orientation = Orientation::Portrait(false);
 
+// The loop states and parameters, the looper state and the position are published by the looper.
 struct Display {
+    looper_state : LooperState,
+    position : u8,
+    scene : Option<usize>,
     loop_states : Vec<LoopState>,
+    loop_gains : Vec<u8>,
+    loop_pans : Vec<u8>,
+    loop_reverse : Vec<bool>,
+    loop_speeds : Vec<LoopSpeed>,
+    xruns : u8,
+    clips : u8,
     lcd : Option<PCD8544>
 }
 
 impl Display {
     fn new(n : usize) -> Self {
         Self {
+            looper_state : LooperState::Idle,
+            position : 0,
+            scene : None,
             loop_states : vec![LoopState::Empty ; n],
+            loop_gains : vec![127 ; n],
+            loop_pans : vec![64 ; n],
+            loop_reverse : vec![false ; n],
+            loop_speeds : vec![LoopSpeed::Normal ; n],
+            xruns : 0,
+            clips : 0,
             lcd : PCD8544::new(LCD_DC, LCD_RST, LCD_SPI, LCD_ORIENT).ok()
         }
     }
 
+    fn loop_char(&self, state : LoopState) -> char {
+        match state {
+            LoopState::Empty     => '\u{2014}', // Em dash
+            // Before the first loop starts, the looper waits for the first note.
+            LoopState::Recording if self.looper_state == LooperState::WaitingFirstNote
+                                 => '\u{25cb}', // White circle
+            LoopState::Recording => '\u{25cf}', // Black circle
+            LoopState::Playing   => '\u{25b6}', // Black right-pointing triangle
+            LoopState::Muted     => '\u{23f8}'  // Double vertical bar
+        }
+    }
+
     fn show(&mut self, kb : &Keyboard) {
+        let loop_chars : Vec<char> = self.loop_states.iter().map(|&l| self.loop_char(l)).collect();
         if self.lcd.is_some() {
             let lcd = self.lcd.as_mut().unwrap();
             lcd.clear();
@@ -44,13 +76,8 @@ impl Display {
                     lcd.print(5, 1, &format!("{:>3} - {:<3}", kb.min_program + 1, kb.min_program + kb.program_keys));
                     lcd.print(0, 2, &format!("T    {:>3}", kb.tempo));
                     lcd.char_spacing = 3;
-                    for (i, l) in (&self.loop_states).iter().enumerate() {
-                        lcd.print_char(i, 3, match *l {
-                            LoopState::Empty     => '\u{2014}', // Em dash
-                            LoopState::Recording => '\u{25cf}', // Black circle
-                            LoopState::Playing   => '\u{25b6}', // Black right-pointing triangle
-                            LoopState::Muted     => '\u{23f8}'  // Double vertical bar
-                        });
+                    for (i, c) in loop_chars.iter().enumerate() {
+                        lcd.print_char(i, 3, *c);
                     }
                 },
 
@@ -73,13 +100,8 @@ impl Display {
                     lcd.print(0, 5, &format!("{:<3}\u{2502}", kb.min_program + 1));
                     lcd.print(0, 6, &format!("{:<3}\u{2502}", kb.min_program + kb.program_keys));
 
-                    for (i, l) in (&self.loop_states).iter().enumerate() {
-                        lcd.print_char(5 + i % 3, 4 + i / 3, match *l {
-                            LoopState::Empty     => '\u{2014}', // Em dash
-                            LoopState::Recording => '\u{25cf}', // Black circle
-                            LoopState::Playing   => '\u{25b6}', // Black right-pointing triangle
-                            LoopState::Muted     => '\u{23f8}'  // Double vertical bar
-                        });
+                    for (i, c) in loop_chars.iter().enumerate() {
+                        lcd.print_char(5 + i % 3, 4 + i / 3, *c);
                     }
                 }
             }
@@ -98,6 +120,12 @@ impl Display {
             println!("Current program: {}", kb.current_program + 1);
         }
         println!("Tempo:           {}", kb.tempo);
+        match self.looper_state {
+            LooperState::Idle               => println!("Looper:          idle"),
+            LooperState::WaitingFirstNote   => println!("Looper:          waiting for the first note"),
+            LooperState::RecordingFirstLoop => println!("Looper:          recording the first loop"),
+            LooperState::Running            => println!("Looper:          running, {}%", self.position as usize * 100 / 128)
+        }
         print!("Loops:           ");
         for l in &self.loop_states {
             let c = match *l {
@@ -109,20 +137,105 @@ impl Display {
             print!("{}", c);
         }
         println!();
+        print!("Loop gain/pan:  ");
+        for (g, p) in self.loop_gains.iter().zip(self.loop_pans.iter()) {
+            print!(" {}/{}", g, p);
+        }
+        println!();
+        print!("Loop playback:  ");
+        for (r, s) in self.loop_reverse.iter().zip(self.loop_speeds.iter()) {
+            let speed = match *s {
+                LoopSpeed::Normal => "1",
+                LoopSpeed::Half   => "0.5",
+                LoopSpeed::Double => "2"
+            };
+            print!(" {}{}", if *r { '-' } else { '+' }, speed);
+        }
+        println!();
+        if let Some(s) = self.scene {
+            println!("Scene:           {}", s + 1);
+        }
+        if kb.effect_target as usize >= self.loop_states.len() {
+            println!("Effect:          {} on the master output", kb.effect.name());
+        }
+        else {
+            println!("Effect:          {} on loop {}", kb.effect.name(), kb.effect_target + 1);
+        }
+        if self.xruns > 0 {
+            println!("Xruns:           {}{}", self.xruns, if self.xruns == 127 { "+" } else { "" });
+        }
+        if self.clips > 0 {
+            println!("Clips:           {}{}", self.clips, if self.clips == 127 { "+" } else { "" });
+        }
     }
 }
 
+// The events published by the looper, on their own input port.
+// The gain and pan of a loop use the same CC numbers as the commands of the keyboard.
 impl LoopManager for Display {
     fn get_loop_count(&self) -> usize {
         self.loop_states.len()
     }
 
-    fn set_loop_state(&mut self, loop_index : usize, _time : usize, state : LoopState) {
+    fn set_loop_state(&mut self, _loop_index : usize, _time : usize, _state : LoopState) {}
+
+    fn get_loop_state(&self, loop_index : usize) -> LoopState {
+        self.loop_states[loop_index]
+    }
+
+    fn set_loop_gain(&mut self, loop_index : usize, _time : usize, gain : u8) {
+        self.loop_gains[loop_index] = gain
+    }
+
+    fn set_loop_pan(&mut self, loop_index : usize, _time : usize, pan : u8) {
+        self.loop_pans[loop_index] = pan
+    }
+
+    fn set_xrun_count(&mut self, _time : usize, count : u8) {
+        self.xruns = count
+    }
+
+    fn set_clip_count(&mut self, _time : usize, count : u8) {
+        self.clips = count
+    }
+
+    fn loop_state_changed(&mut self, loop_index : usize, _time : usize, state : LoopState) {
         self.loop_states[loop_index] = state
     }
 
+    fn loop_mode_changed(&mut self, loop_index : usize, _time : usize, reverse : bool, speed : LoopSpeed) {
+        self.loop_reverse[loop_index] = reverse;
+        self.loop_speeds[loop_index]  = speed
+    }
+
+    fn looper_state_changed(&mut self, _time : usize, state : LooperState) {
+        self.looper_state = state
+    }
+
+    fn set_loop_position(&mut self, _time : usize, position : u8) {
+        self.position = position
+    }
+}
+
+// The commands of the keyboard.
+// The looper may delay or reject them: the loops are updated when the looper publishes their state.
+struct Commands<'a> {
+    display : &'a mut Display
+}
+
+impl<'a> LoopManager for Commands<'a> {
+    fn get_loop_count(&self) -> usize {
+        self.display.loop_states.len()
+    }
+
+    fn set_loop_state(&mut self, _loop_index : usize, _time : usize, _state : LoopState) {}
+
     fn get_loop_state(&self, loop_index : usize) -> LoopState {
-        self.loop_states[loop_index]
+        self.display.loop_states[loop_index]
+    }
+
+    fn recall_scene(&mut self, scene_index : usize, _time : usize) {
+        self.display.scene = Some(scene_index)
     }
 }
 
@@ -132,15 +245,21 @@ fn main() {
     let mut keyboard = Keyboard::new();
     display.show(&keyboard);
 
-    // Open Jack client and register MIDI input port.
+    // Open Jack client and register MIDI input ports for the keyboard and the looper.
     let (client, _status) = jack::Client::new("midi314-display", jack::ClientOptions::NO_START_SERVER).unwrap();
-    let midi_in = client.register_port("midi_in", jack::MidiIn::default()).unwrap();
+    let midi_in   = client.register_port("midi_in", jack::MidiIn::default()).unwrap();
+    let looper_in = client.register_port("looper_in", jack::MidiIn::default()).unwrap();
 
     // Show MIDI messages.
     let cback = move |_ : &jack::Client, ps : &jack::ProcessScope| -> jack::Control {
         let mut has_event = false;
 
         for e in midi_in.iter(ps) {
+            if keyboard.update(&mut Commands { display : &mut display }, e.time as usize, e.bytes.to_vec()) {
+                has_event = true;
+            }
+        }
+        for e in looper_in.iter(ps) {
             if keyboard.update(&mut display, e.time as usize, e.bytes.to_vec()) {
                 has_event = true;
             }
@@ -0,0 +1,62 @@
+
+// A global allocator that counts the memory allocations made in the process callback.
+// It is used in debug mode to check that the audio engine is realtime-safe.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+pub struct CheckedAllocator;
+
+static ENABLED     : AtomicBool  = AtomicBool::new(false);
+static ALLOCATIONS : AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    static IN_CALLBACK : Cell<bool> = const { Cell::new(false) };
+}
+
+fn count() {
+    if ENABLED.load(Ordering::Relaxed) && IN_CALLBACK.try_with(|c| c.get()).unwrap_or(false) {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+unsafe impl GlobalAlloc for CheckedAllocator {
+    unsafe fn alloc(&self, layout : Layout) -> *mut u8 {
+        count();
+        System.alloc(layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout : Layout) -> *mut u8 {
+        count();
+        System.alloc_zeroed(layout)
+    }
+
+    unsafe fn realloc(&self, ptr : *mut u8, layout : Layout, new_size : usize) -> *mut u8 {
+        count();
+        System.realloc(ptr, layout, new_size)
+    }
+
+    unsafe fn dealloc(&self, ptr : *mut u8, layout : Layout) {
+        count();
+        System.dealloc(ptr, layout)
+    }
+}
+
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+// Mark the beginning and the end of the process callback in the current thread.
+pub fn enter_callback() {
+    IN_CALLBACK.with(|c| c.set(true));
+}
+
+pub fn leave_callback() {
+    IN_CALLBACK.with(|c| c.set(false));
+}
+
+// Get the number of allocations since the previous call.
+pub fn take_count() -> usize {
+    ALLOCATIONS.swap(0, Ordering::Relaxed)
+}
@@ -0,0 +1,98 @@
+
+use std::{cmp, process, thread, time};
+use jack;
+use ringbuf::RingBuffer;
+use config::{Config, MAX_CHANNELS, db_to_power};
+
+// The number of measurements.
+const PING_COUNT : usize = 8;
+
+// The note sent to the synthesizer.
+const PING_NOTE_ON  : [u8 ; 3] = [0x90, 60, 100];
+const PING_NOTE_OFF : [u8 ; 3] = [0x80, 60, 0];
+
+// Measure the delay between a note sent to the given MIDI port and its sound on the audio inputs.
+// The MIDI output of the looper is connected to the port during the measurement.
+pub fn calibrate(config : &Config, client : jack::Client, target : &str) {
+    let mut midi_out = client.register_port("midi_out", jack::MidiOut::default()).unwrap();
+    let     audio_in : Vec<_> = (1 ..= config.channels).map(|c| client.register_port(&format!("audio_in_{}", c), jack::AudioIn::default()).unwrap()).collect();
+    let midi_out_name = midi_out.name().to_string();
+
+    let (mut producer, mut consumer) = RingBuffer::<u32>::new(PING_COUNT).split();
+
+    // Send a note every half-second, and wait at most one second for its sound.
+    let sample_rate = client.sample_rate() as u32;
+    let interval    = sample_rate / 2;
+    let threshold   = db_to_power(config.threshold);
+    let mut ping : Option<u32> = None;
+    let mut wait = interval;
+
+    let cback = move |_ : &jack::Client, ps : &jack::ProcessScope| -> jack::Control {
+        let frame_time = ps.last_frame_time();
+        let n_frames   = ps.n_frames();
+        let mut writer = midi_out.writer(ps);
+
+        let mut inputs : [&[f32] ; MAX_CHANNELS] = Default::default();
+        for (i, p) in inputs.iter_mut().zip(audio_in.iter()) {
+            *i = p.as_slice(ps);
+        }
+        let inputs = &inputs[..audio_in.len()];
+        let onset = (0 .. n_frames as usize).position(|k| inputs.iter().map(|input| input[k] * input[k]).sum::<f32>() > threshold);
+
+        match ping {
+            Some(t) => {
+                let result = onset.map(|k| frame_time.wrapping_add(k as u32).wrapping_sub(t));
+                if let Some(latency) = result {
+                    let _ = producer.push(latency);
+                }
+                if result.is_some() || frame_time.wrapping_sub(t) > sample_rate {
+                    let _ = writer.write(&jack::RawMidi { time : 0, bytes : &PING_NOTE_OFF });
+                    ping = None;
+                    wait = interval;
+                }
+            },
+            None => {
+                wait = wait.saturating_sub(n_frames);
+                // Send the next note when the previous one has faded out.
+                if wait == 0 && onset.is_none() {
+                    let _ = writer.write(&jack::RawMidi { time : 0, bytes : &PING_NOTE_ON });
+                    ping = Some(frame_time);
+                }
+            }
+        }
+
+        jack::Control::Continue
+    };
+
+    let buffer_size = client.buffer_size();
+    let active_client = client.activate_async((), jack::ClosureProcessHandler::new(cback)).unwrap();
+    if let Err(e) = active_client.as_client().connect_ports_by_name(&midi_out_name, target) {
+        eprintln!("Could not connect {} to {}: {:?}", midi_out_name, target, e);
+        process::exit(1)
+    }
+
+    let start = time::Instant::now();
+    let mut results = Vec::with_capacity(PING_COUNT);
+    while results.len() < PING_COUNT {
+        while let Some(latency) = consumer.pop() {
+            println!("Round trip: {} samples ({:.1} ms)", latency, to_ms(latency, sample_rate));
+            results.push(latency);
+        }
+        if start.elapsed() >= time::Duration::from_secs(2 * PING_COUNT as u64) {
+            eprintln!("No sound received on the audio inputs");
+            process::exit(1)
+        }
+        thread::sleep(time::Duration::from_millis(10))
+    }
+
+    // Looper commands are already delayed by one period.
+    results.sort();
+    let round_trip = results[PING_COUNT / 2];
+    let latency    = cmp::max(round_trip, buffer_size) - buffer_size;
+    println!("Median round trip: {} samples ({:.1} ms)", round_trip, to_ms(round_trip, sample_rate));
+    println!("Suggested option: --latency {:.1}", to_ms(latency, sample_rate));
+}
+
+fn to_ms(samples : u32, sample_rate : u32) -> f32 {
+    samples as f32 * 1000.0 / sample_rate as f32
+}
@@ -0,0 +1,227 @@
+
+use std::cmp;
+use midi314::{MIN_TEMPO, MAX_TEMPO};
+use control::MidiEvent;
+use midi_loop::TimedEvent;
+use transport::loop_beats;
+
+// The number of clock events per beat.
+const PPQN : usize = 24;
+
+// The maximum number of clock events sent in each period.
+const MAX_CLOCK_EVENTS : usize = 64;
+
+// MIDI system real-time and common messages.
+const CLOCK         : u8 = 0xF8;
+const START         : u8 = 0xFA;
+const STOP          : u8 = 0xFC;
+const SONG_POSITION : u8 = 0xF2;
+
+// The role of the looper with respect to MIDI clock.
+#[derive(Clone, Copy, PartialEq)]
+pub enum ClockMode {
+    // Send clock events derived from the master loop.
+    Send,
+    // Set the tempo from the clock events received.
+    Follow
+}
+
+// The audio engine side of the MIDI clock output.
+// Before the first loop is recorded, the clock runs at the current tempo.
+// When the master loop starts, the clock is restarted at the beginning of the song,
+// and the tempo is adjusted so that the master loop contains a whole number of beats.
+pub struct MidiClock {
+    pub output : Vec<TimedEvent>,
+    // The number of samples until the next clock event, before the master loop starts.
+    phase   : f64,
+    started : bool
+}
+
+impl MidiClock {
+    pub fn new() -> Self {
+        Self {
+            output  : Vec::with_capacity(MAX_CLOCK_EVENTS),
+            phase   : 0.0,
+            started : false
+        }
+    }
+
+    fn send(&mut self, time : usize, bytes : &[u8]) {
+        if self.output.len() < self.output.capacity() {
+            if let Some(event) = MidiEvent::new(0, bytes) {
+                self.output.push(TimedEvent { time : time, event : event });
+            }
+        }
+    }
+
+    // Send the clock events of a period of the given length.
+    // The master loop, if any, is given by its length and the position of the cursor at the beginning of the period.
+    pub fn run(&mut self, len : usize, tempo : u32, sample_rate : usize, master : Option<(usize, usize)>) {
+        let mut time = 0;
+        if let Some((length, position)) = master {
+            // The master loop starts when the cursor wraps around.
+            let start = (length - position) % length;
+            if !self.started && start < len {
+                self.send(start, &[SONG_POSITION, 0, 0]);
+                self.send(start, &[START]);
+                self.started = true;
+                self.phase   = 0.0;
+                time = start;
+            }
+            if self.started {
+                self.run_master(time, len, tempo as f64, sample_rate as f64, length, position);
+                return
+            }
+        }
+        else if self.started {
+            self.send(0, &[STOP]);
+            self.started = false;
+        }
+
+        let tick_length = 60.0 * sample_rate as f64 / (tempo as f64 * PPQN as f64);
+        while (time as f64 + self.phase) < len as f64 {
+            let t = time + self.phase as usize;
+            self.send(t, &[CLOCK]);
+            self.phase += tick_length;
+        }
+        self.phase -= (len - time) as f64;
+    }
+
+    fn run_master(&mut self, time : usize, len : usize, tempo : f64, sample_rate : f64, length : usize, position : usize) {
+        // Clock events are aligned with the beginning of the master loop.
+        let ticks       = loop_beats(length, tempo, sample_rate) * PPQN as f64;
+        let tick_length = length as f64 / ticks;
+        let mut k = ((position + time) as f64 / tick_length).ceil();
+        loop {
+            let offset = k * tick_length - position as f64;
+            if offset >= len as f64 {
+                break
+            }
+            self.send(cmp::min(offset as usize, len - 1), &[CLOCK]);
+            k += 1.0;
+        }
+    }
+}
+
+// The control side of the MIDI clock input.
+// The tempo is measured over each beat of the clock events received.
+pub struct ClockFollower {
+    last_beat : Option<u32>,
+    ticks     : usize
+}
+
+impl ClockFollower {
+    pub fn new() -> Self {
+        Self {
+            last_beat : None,
+            ticks     : 0
+        }
+    }
+
+    // Process a MIDI event received at the given frame time.
+    // Return the new tempo at the end of each beat.
+    pub fn update(&mut self, time : u32, event : &MidiEvent, sample_rate : usize) -> Option<u32> {
+        match event.bytes[0] {
+            CLOCK => {
+                self.ticks += 1;
+                if let Some(last) = self.last_beat {
+                    if self.ticks < PPQN {
+                        return None
+                    }
+                    self.ticks     = 0;
+                    self.last_beat = Some(time);
+                    let elapsed = time.wrapping_sub(last);
+                    if elapsed > 0 {
+                        let tempo = (60.0 * sample_rate as f64 / elapsed as f64).round() as u32;
+                        return Some(tempo.clamp(MIN_TEMPO, MAX_TEMPO))
+                    }
+                }
+                else {
+                    self.ticks     = 0;
+                    self.last_beat = Some(time);
+                }
+                None
+            },
+            START | STOP => {
+                // Measure the tempo from the first clock event after a start or a stop.
+                self.last_beat = None;
+                None
+            },
+            _ => None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use control::MidiEvent;
+    use super::{ClockFollower, MidiClock, CLOCK, PPQN, START};
+
+    const SAMPLE_RATE : usize = 48000;
+
+    // Run the clock over the given number of periods and return the absolute times of the clock events.
+    // With a master loop, the cursor is at the beginning of the loop at time 0.
+    fn clock_times(len : usize, periods : usize, tempo : u32, master : Option<usize>) -> Vec<usize> {
+        let mut clock = MidiClock::new();
+        let mut times = Vec::new();
+        for p in 0 .. periods {
+            clock.output.clear();
+            clock.run(len, tempo, SAMPLE_RATE, master.map(|length| (length, p * len % length)));
+            for e in &clock.output {
+                assert!(e.time < len);
+                if e.event.bytes[0] == CLOCK {
+                    times.push(p * len + e.time);
+                }
+            }
+        }
+        times
+    }
+
+    #[test]
+    fn free_running_ticks() {
+        // At 120 BPM, a beat lasts 24000 samples and a tick 1000 samples.
+        let times = clock_times(256, 400, 120, None);
+        assert_eq!(times.len(), 400 * 256 / 1000 + 1);
+        for (k, &t) in times.iter().enumerate() {
+            assert_eq!(t, k * 1000);
+        }
+    }
+
+    #[test]
+    fn master_loop_ticks() {
+        // A loop of 100000 samples at 120 BPM is adjusted to 4 beats, with ticks every 1041.7 samples.
+        let length = 100000;
+        for &len in &[250, 256] {
+            let times = clock_times(len, 2 * length / len, 120, Some(length));
+            assert_eq!(times.len(), 2 * 4 * PPQN);
+            assert_eq!(times[0], 0);
+            assert!(times[4 * PPQN] >= length - 1 && times[4 * PPQN] <= length);
+            for w in times.windows(2) {
+                assert!(w[1] - w[0] >= 1040 && w[1] - w[0] <= 1042);
+            }
+        }
+    }
+
+    #[test]
+    fn start_on_master_loop() {
+        // The clock starts when the cursor wraps around a master loop of one beat.
+        let mut clock = MidiClock::new();
+        clock.run(256, 120, SAMPLE_RATE, Some((24000, 23900)));
+        let events : Vec<(usize, u8)> = clock.output.iter().map(|e| (e.time, e.event.bytes[0])).collect();
+        assert_eq!(&events[1 ..], &[(100, START), (100, CLOCK)]);
+    }
+
+    #[test]
+    fn follow_tempo() {
+        let mut follower = ClockFollower::new();
+        let clock = MidiEvent::new(0, &[CLOCK]).unwrap();
+        let mut tempos = Vec::new();
+        // Ticks every 1250 samples make a beat of 30000 samples, or 96 BPM.
+        for k in 0 .. 2 * PPQN as u32 + 1 {
+            if let Some(tempo) = follower.update(k * 1250, &clock, SAMPLE_RATE) {
+                tempos.push(tempo);
+            }
+        }
+        assert_eq!(tempos, vec![96, 96]);
+    }
+}
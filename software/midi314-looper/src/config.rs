@@ -0,0 +1,272 @@
+
+use std::{env, process};
+use std::net::{IpAddr, Ipv4Addr};
+use std::str::FromStr;
+use getopts::{Matches, Options};
+use midi314::MonitorMode;
+use clock::ClockMode;
+use limiter::LimiterMode;
+use transport::TransportMode;
+
+// The maximum number of audio channels.
+pub const MAX_CHANNELS : usize = 8;
+
+#[derive(Clone)]
+pub struct Config {
+    pub channels   : usize,
+    pub undo_depth : usize,
+    pub fade_in    : f32,
+    pub fade_out   : f32,
+    pub crossfade  : f32,
+    pub max_length : f32,
+    pub memory     : f32,
+    pub threshold  : f32,
+    pub pre_roll   : f32,
+    pub trigger_on_note : bool,
+    pub tempo_follow    : bool,
+    pub midi_loops      : Vec<usize>,
+    pub transport       : Option<TransportMode>,
+    pub beats_per_bar   : usize,
+    pub midi_clock      : Option<ClockMode>,
+    pub osc_port        : Option<u16>,
+    pub osc_address     : IpAddr,
+    pub session         : Option<String>,
+    pub limiter         : LimiterMode,
+    pub limiter_ceiling : f32,
+    pub auto_gain       : bool,
+    pub meters          : bool,
+    pub monitoring      : MonitorMode,
+    pub multi_output    : bool,
+    pub latency    : Option<f32>,
+    pub calibrate  : Option<String>,
+    pub reconnect  : bool,
+    pub check_alloc : bool,
+    pub render      : Option<String>,
+    pub events      : Option<String>,
+    pub output      : String,
+    pub buffer_size : usize
+}
+
+// The durations from the configuration, converted into numbers of samples,
+// with the sample rate used for the conversion.
+#[derive(Clone, Copy)]
+pub struct Timing {
+    pub fade_in   : usize,
+    pub fade_out  : usize,
+    pub crossfade : usize,
+    pub latency   : usize,
+    pub sample_rate : usize
+}
+
+impl Config {
+    pub fn from_args() -> Self {
+        let args : Vec<String> = env::args().collect();
+        Self::parse(&args)
+    }
+
+    // Read the configuration from a command line, starting with the program name.
+    pub fn parse(args : &[String]) -> Self {
+        let mut opts = Options::new();
+        opts.optopt("", "channels", &format!("Number of audio input and output channels, from 1 to {} (default: 2).", MAX_CHANNELS), "N");
+        opts.optopt("", "undo-depth", "Number of previous recordings kept for each loop (default: 1). Previous recordings use memory from the shared pool (see --memory).", "N");
+        opts.optopt("", "fade-in", "Duration of the fade-in when a loop starts playing, in milliseconds (default: 5).", "MS");
+        opts.optopt("", "fade-out", "Duration of the fade-out when a loop is muted or deleted, in milliseconds (default: 10).", "MS");
+        opts.optopt("", "crossfade", "Duration of the crossfade at the end of a recording, in milliseconds (default: 10, 0 to disable).", "MS");
+        opts.optopt("", "max-length", "Maximum length of a loop, in seconds (default: 60).", "S");
+        opts.optopt("", "memory", "Total duration of audio kept by all loops and undo levels, in seconds (default: 180).", "S");
+        opts.optopt("", "threshold", "Input level that starts the recording of the first loop, in dBFS (default: -40).", "DB");
+        opts.optopt("", "pre-roll", "Duration of the input kept before the first note of the first loop, in milliseconds (default: 20).", "MS");
+        opts.optflag("", "trigger-on-note", "Start the first loop at the first MIDI note-on event instead of the input level.");
+        opts.optflag("", "tempo-follow", "Time-stretch the loops when the tempo changes, so that they keep their length in beats.");
+        opts.optopt("", "midi-loops", "Comma-separated numbers of the loops, from 1 to 9, that record MIDI events instead of audio. With MIDI loops, use --trigger-on-note.", "LIST");
+        opts.optopt("", "transport", "Synchronize with the Jack transport: 'master' publishes the tempo and the position in bars, 'follower' starts and stops the loops with the transport and starts the first loop on a bar.", "MODE");
+        opts.optopt("", "beats-per-bar", "With --transport master, the number of beats in a bar (default: 4).", "N");
+        opts.optopt("", "midi-clock", "Synchronize with MIDI clock: 'send' sends clock, start and stop events on the clock_out port, 'follow' sets the tempo from the clock events received on the midi_in port, and adjusts the first loop to a whole number of beats.", "MODE");
+        opts.optopt("", "osc-port", "Accept OSC messages on the given UDP port, and send notifications to the clients that register with /register.", "PORT");
+        opts.optopt("", "osc-address", "With --osc-port, the local address on which OSC messages are accepted (default: 127.0.0.1). Use 0.0.0.0 to accept messages from the network.", "ADDRESS");
+        opts.optopt("", "session", "Text file where the tempo and the loop settings are saved with the OSC message /session/save. The session is restored at startup if the file exists. The recordings are not saved.", "FILE");
+        opts.optopt("", "limiter", "Keep the master output below the ceiling: 'lookahead' reduces the gain before the peaks and delays the output by 1.5 ms, 'soft-clip' bends the peaks without delay, 'off' disables the limiter (default: lookahead).", "MODE");
+        opts.optopt("", "limiter-ceiling", "Maximum level of the master output, in dBFS (default: -1).", "DB");
+        opts.optflag("", "auto-gain", "Divide the gain of the master output by the square root of the number of playing loops.");
+        opts.optopt("", "monitoring", "When the input is passed through to the outputs: 'always', 'never', 'recording' while a loop is recording, 'idle' before the first loop is recorded (default: always). Use 'never' when the synthesizer is also connected to the speakers.", "MODE");
+        opts.optflag("", "multi-output", "Register output ports for each loop (loop_N_out_1, loop_N_out_2...) with the loop alone, after its gain, pan and effects, and ports for the dry input (dry_out_1, dry_out_2...). The audio_out ports carry the master output. All these ports are delayed by the lookahead of the limiter.");
+        opts.optflag("", "meters", "Print the peak levels of the input, the master output and the loops in the terminal.");
+        opts.optopt("", "latency", "Delay of the recorded input with respect to the MIDI events, in milliseconds (default: the capture latency reported by Jack).", "MS");
+        opts.optopt("", "calibrate", "Measure the latency by sending notes to the given MIDI port and listening to the input.", "PORT");
+        opts.optflag("", "reconnect", "Wait for the Jack server to restart when it shuts down, instead of exiting. The loops are lost.");
+        opts.optflag("", "check-alloc", "Debug mode: report memory allocations in the audio thread.");
+        opts.optopt("", "render", "Process a WAV file offline instead of connecting to the Jack server.", "FILE");
+        opts.optopt("", "events", "With --render, read timed MIDI events from a text file: one event per line, with the time in samples followed by the bytes of the event in hexadecimal.", "FILE");
+        opts.optopt("", "output", "With --render, the WAV file to write (default: output.wav).", "FILE");
+        opts.optopt("", "buffer-size", "With --render, the number of samples processed in each period (default: 256).", "N");
+        opts.optflag("h", "help", "Print this help message.");
+
+        let matches = match opts.parse(&args[1..]) {
+            Ok(m)  => m,
+            Err(e) => {
+                eprintln!("{}", e);
+                process::exit(1)
+            }
+        };
+
+        if matches.opt_present("help") {
+            print!("{}", opts.usage(&format!("Usage: {} [options]", args[0])));
+            process::exit(0)
+        }
+
+        let channels = get_opt(&matches, "channels", 2);
+        if !(1 ..= MAX_CHANNELS).contains(&channels) {
+            eprintln!("The number of channels must be between 1 and {}", MAX_CHANNELS);
+            process::exit(1)
+        }
+
+        let buffer_size = get_opt(&matches, "buffer-size", 256);
+        if buffer_size == 0 {
+            eprintln!("The buffer size must be greater than 0");
+            process::exit(1)
+        }
+
+        let midi_loops = match matches.opt_str("midi-loops") {
+            Some(s) => s.split(',').map(|n| match n.trim().parse::<usize>() {
+                Ok(n) if (1 ..= 9).contains(&n) => n - 1,
+                _ => {
+                    eprintln!("Invalid loop number in option --midi-loops: {}", n);
+                    process::exit(1)
+                }
+            }).collect(),
+            None => Vec::new()
+        };
+
+        let transport = match matches.opt_str("transport") {
+            Some(ref s) if s == "master"   => Some(TransportMode::Master),
+            Some(ref s) if s == "follower" => Some(TransportMode::Follower),
+            Some(s) => {
+                eprintln!("Invalid value for option --transport: {}", s);
+                process::exit(1)
+            },
+            None => None
+        };
+
+        let midi_clock = match matches.opt_str("midi-clock") {
+            Some(ref s) if s == "send"   => Some(ClockMode::Send),
+            Some(ref s) if s == "follow" => Some(ClockMode::Follow),
+            Some(s) => {
+                eprintln!("Invalid value for option --midi-clock: {}", s);
+                process::exit(1)
+            },
+            None => None
+        };
+
+        let limiter = match matches.opt_str("limiter") {
+            Some(ref s) if s == "lookahead" => LimiterMode::Lookahead,
+            Some(ref s) if s == "soft-clip" => LimiterMode::SoftClip,
+            Some(ref s) if s == "off"       => LimiterMode::Off,
+            Some(s) => {
+                eprintln!("Invalid value for option --limiter: {}", s);
+                process::exit(1)
+            },
+            None => LimiterMode::Lookahead
+        };
+
+        let monitoring = match matches.opt_str("monitoring") {
+            Some(ref s) if s == "always"    => MonitorMode::Always,
+            Some(ref s) if s == "never"     => MonitorMode::Never,
+            Some(ref s) if s == "recording" => MonitorMode::Recording,
+            Some(ref s) if s == "idle"      => MonitorMode::Idle,
+            Some(s) => {
+                eprintln!("Invalid value for option --monitoring: {}", s);
+                process::exit(1)
+            },
+            None => MonitorMode::Always
+        };
+
+        let limiter_ceiling = get_opt(&matches, "limiter-ceiling", -1.0);
+        if limiter_ceiling > 0.0 {
+            eprintln!("The limiter ceiling must not be greater than 0 dBFS");
+            process::exit(1)
+        }
+
+        let beats_per_bar = get_opt(&matches, "beats-per-bar", 4);
+        if beats_per_bar == 0 {
+            eprintln!("The number of beats per bar must be greater than 0");
+            process::exit(1)
+        }
+
+        Self {
+            channels   : channels,
+            undo_depth : get_opt(&matches, "undo-depth", 1),
+            fade_in    : get_opt(&matches, "fade-in", 5.0),
+            fade_out   : get_opt(&matches, "fade-out", 10.0),
+            crossfade  : get_opt(&matches, "crossfade", 10.0),
+            max_length : get_opt(&matches, "max-length", 60.0),
+            memory     : get_opt(&matches, "memory", 180.0),
+            threshold  : get_opt(&matches, "threshold", -40.0),
+            pre_roll   : get_opt(&matches, "pre-roll", 20.0),
+            trigger_on_note : matches.opt_present("trigger-on-note"),
+            tempo_follow    : matches.opt_present("tempo-follow"),
+            midi_loops      : midi_loops,
+            transport       : transport,
+            beats_per_bar   : beats_per_bar,
+            midi_clock      : midi_clock,
+            osc_port        : matches.opt_str("osc-port").map(|_| get_opt(&matches, "osc-port", 0)),
+            osc_address     : get_opt(&matches, "osc-address", IpAddr::V4(Ipv4Addr::LOCALHOST)),
+            session         : matches.opt_str("session"),
+            limiter         : limiter,
+            limiter_ceiling : limiter_ceiling,
+            auto_gain       : matches.opt_present("auto-gain"),
+            meters          : matches.opt_present("meters"),
+            monitoring      : monitoring,
+            multi_output    : matches.opt_present("multi-output"),
+            latency    : matches.opt_str("latency").map(|_| get_opt(&matches, "latency", 0.0)),
+            calibrate  : matches.opt_str("calibrate"),
+            reconnect  : matches.opt_present("reconnect"),
+            check_alloc : matches.opt_present("check-alloc"),
+            render      : matches.opt_str("render"),
+            events      : matches.opt_str("events"),
+            output      : matches.opt_str("output").unwrap_or_else(|| String::from("output.wav")),
+            buffer_size : buffer_size
+        }
+    }
+}
+
+impl Config {
+    pub fn timing(&self, sample_rate : usize) -> Timing {
+        Timing {
+            fade_in   : ms_to_samples(self.fade_in,   sample_rate),
+            fade_out  : ms_to_samples(self.fade_out,  sample_rate),
+            crossfade : ms_to_samples(self.crossfade, sample_rate),
+            latency   : ms_to_samples(self.latency.unwrap_or(0.0), sample_rate),
+            sample_rate : sample_rate
+        }
+    }
+}
+
+// Convert a duration in milliseconds into a number of samples.
+pub fn ms_to_samples(ms : f32, sample_rate : usize) -> usize {
+    (ms * sample_rate as f32 / 1000.0) as usize
+}
+
+// Convert a duration in seconds into a number of samples.
+pub fn seconds_to_samples(s : f32, sample_rate : usize) -> usize {
+    (s * sample_rate as f32) as usize
+}
+
+// Convert a level in dBFS into a signal power.
+pub fn db_to_power(db : f32) -> f32 {
+    10.0f32.powf(db / 10.0)
+}
+
+// Convert a level in dBFS into a signal amplitude.
+pub fn db_to_amplitude(db : f32) -> f32 {
+    10.0f32.powf(db / 20.0)
+}
+
+fn get_opt<T : FromStr>(matches : &Matches, name : &str, default : T) -> T {
+    match matches.opt_str(name) {
+        Some(s) => s.parse().unwrap_or_else(|_| {
+            eprintln!("Invalid value for option --{}: {}", name, s);
+            process::exit(1)
+        }),
+        None => default
+    }
+}
@@ -0,0 +1,322 @@
+
+use ringbuf::{Consumer, Producer, RingBuffer};
+use midi314::{Effect, LoopManager, LoopSpeed, LoopState, MonitorMode, DEFAULT_EFFECT_PARAMS, EFFECT_COUNT, EFFECT_PARAM_COUNT};
+use config::Timing;
+use effects::{self, EffectMemory};
+use scene::{Scene, SCENE_COUNT};
+
+// The capacity of the queues between the control side and the audio engine.
+pub const QUEUE_CAPACITY : usize = 256;
+
+// The number of loops.
+pub const LOOP_COUNT : usize = 9;
+
+// The default gain and pan of a loop, as CC values.
+pub const DEFAULT_GAIN : u8 = 127;
+pub const DEFAULT_PAN  : u8 = 64;
+
+// A MIDI event received in the process callback.
+// Time is the absolute Jack frame time of the event.
+#[derive(Clone, Copy)]
+pub struct MidiEvent {
+    pub time  : u32,
+    pub len   : usize,
+    pub bytes : [u8 ; 3]
+}
+
+impl MidiEvent {
+    pub fn new(time : u32, bytes : &[u8]) -> Option<Self> {
+        // Looper commands are short messages: ignore System Exclusive events.
+        if bytes.is_empty() || bytes.len() > 3 {
+            return None
+        }
+        let mut event = Self {
+            time  : time,
+            len   : bytes.len(),
+            bytes : [0 ; 3]
+        };
+        event.bytes[..bytes.len()].copy_from_slice(bytes);
+        Some(event)
+    }
+
+    pub fn is_note_on(&self) -> bool {
+        // A note-on event with velocity 0 is a note-off.
+        self.len == 3 && self.bytes[0] & 0xF0 == 0x90 && self.bytes[2] > 0
+    }
+
+    pub fn to_vec(self) -> Vec<u8> {
+        self.bytes[..self.len].to_vec()
+    }
+}
+
+// The operations that the control side requests from the audio engine.
+#[derive(Clone, Copy)]
+pub enum Command {
+    SetState(usize, LoopState),
+    Undo(usize),
+    Redo(usize),
+    SetGain(usize, u8),
+    SetPan(usize, u8),
+    SetReverse(usize, bool),
+    SetSpeed(usize, LoopSpeed),
+    SetProgram(usize, u8),
+    SetEffectParam(usize, Effect, usize, u8),
+    SetTempo(u32),
+    SetMonitoring(MonitorMode),
+    RecallScene(Scene),
+    SetTiming(Timing)
+}
+
+#[derive(Clone, Copy)]
+pub struct TimedCommand {
+    pub time    : u32,
+    pub command : Command
+}
+
+// Create the queues from the control side to the audio engine, for the given number of loops.
+// The memory of the effects is allocated for the given number of channels and sample rate.
+pub fn channel(n_loops : usize, channels : usize, sample_rate : usize) -> (Controller, CommandReceiver) {
+    let (command_producer, command_consumer) = RingBuffer::new(QUEUE_CAPACITY).split();
+    let (memory_producer, memory_consumer)   = RingBuffer::new(n_loops + 1).split();
+    let effect_memory = EffectAllocator {
+        channels    : channels,
+        sample_rate : sample_rate,
+        allocated   : vec![false ; n_loops + 1],
+        memory      : memory_producer
+    };
+    (Controller::new(n_loops, command_producer, effect_memory), CommandReceiver::new(command_consumer, memory_consumer))
+}
+
+// The control side of the memory of the effects.
+struct EffectAllocator {
+    channels    : usize,
+    sample_rate : usize,
+    // The targets whose memory has been sent to the audio engine.
+    allocated   : Vec<bool>,
+    memory      : Producer<(usize, EffectMemory)>
+}
+
+// The control side of the looper.
+// It keeps its own copy of the loop states to implement the LoopManager operations,
+// and sends commands to the audio engine.
+pub struct Controller {
+    loop_states : Vec<LoopState>,
+    loop_gains  : Vec<u8>,
+    loop_pans   : Vec<u8>,
+    loop_reverse : Vec<bool>,
+    loop_speeds : Vec<LoopSpeed>,
+    // The effect parameters of each loop, then of the master output.
+    effect_params : Vec<[[u8 ; EFFECT_PARAM_COUNT] ; EFFECT_COUNT]>,
+    scenes      : Vec<Option<Scene>>,
+    // The monitoring mode selected on the keyboard, if it has been changed.
+    monitoring  : Option<MonitorMode>,
+    commands    : Producer<TimedCommand>,
+    effect_memory : EffectAllocator
+}
+
+impl Controller {
+    fn new(n_loops : usize, commands : Producer<TimedCommand>, effect_memory : EffectAllocator) -> Self {
+        Self {
+            loop_states : vec![LoopState::Empty ; n_loops],
+            loop_gains  : vec![DEFAULT_GAIN ; n_loops],
+            loop_pans   : vec![DEFAULT_PAN ; n_loops],
+            loop_reverse : vec![false ; n_loops],
+            loop_speeds : vec![LoopSpeed::Normal ; n_loops],
+            effect_params : vec![[DEFAULT_EFFECT_PARAMS ; EFFECT_COUNT] ; n_loops + 1],
+            scenes      : vec![None ; SCENE_COUNT],
+            monitoring  : None,
+            commands    : commands,
+            effect_memory : effect_memory
+        }
+    }
+
+    // Send the memory of the delays and reverbs of a target to the audio engine,
+    // the first time that one of these effects is enabled.
+    fn allocate_effect_memory(&mut self, target : usize) {
+        let a = &mut self.effect_memory;
+        if !a.allocated[target] {
+            a.allocated[target] = true;
+            if a.memory.push((target, EffectMemory::new(a.channels, a.sample_rate))).is_err() {
+                eprintln!("Effect memory queue is full");
+            }
+        }
+    }
+
+    // Update the timing of the audio engine after a change of the latency.
+    pub fn set_timing(&mut self, time : usize, timing : Timing) {
+        self.send(time, Command::SetTiming(timing));
+    }
+
+    pub fn get_monitoring(&self) -> Option<MonitorMode> {
+        self.monitoring
+    }
+
+    // Create the queues to a new audio engine with the given sample rate, where all loops are empty.
+    // The other settings are kept, and must be sent again to the new engine.
+    pub fn reconnect(&mut self, sample_rate : usize) -> CommandReceiver {
+        let (controller, commands) = channel(self.loop_states.len(), self.effect_memory.channels, sample_rate);
+        self.commands      = controller.commands;
+        self.effect_memory = controller.effect_memory;
+        for s in &mut self.loop_states {
+            *s = LoopState::Empty;
+        }
+        commands
+    }
+
+    fn send(&mut self, time : usize, command : Command) {
+        let c = TimedCommand {
+            time    : time as u32,
+            command : command
+        };
+        if self.commands.push(c).is_err() {
+            eprintln!("Looper command queue is full");
+        }
+    }
+}
+
+impl LoopManager for Controller {
+    fn get_loop_count(&self) -> usize {
+        self.loop_states.len()
+    }
+
+    fn set_loop_state(&mut self, loop_index : usize, time : usize, state : LoopState) {
+        self.loop_states[loop_index] = state;
+        self.send(time, Command::SetState(loop_index, state));
+    }
+
+    fn get_loop_state(&self, loop_index : usize) -> LoopState {
+        self.loop_states[loop_index]
+    }
+
+    fn loop_state_changed(&mut self, loop_index : usize, _time : usize, state : LoopState) {
+        self.loop_states[loop_index] = state;
+    }
+
+    fn undo(&mut self, loop_index : usize, time : usize) {
+        self.send(time, Command::Undo(loop_index));
+    }
+
+    fn redo(&mut self, loop_index : usize, time : usize) {
+        self.send(time, Command::Redo(loop_index));
+    }
+
+    fn set_loop_gain(&mut self, loop_index : usize, time : usize, gain : u8) {
+        self.loop_gains[loop_index] = gain;
+        self.send(time, Command::SetGain(loop_index, gain));
+    }
+
+    fn get_loop_gain(&self, loop_index : usize) -> u8 {
+        self.loop_gains[loop_index]
+    }
+
+    fn set_loop_pan(&mut self, loop_index : usize, time : usize, pan : u8) {
+        self.loop_pans[loop_index] = pan;
+        self.send(time, Command::SetPan(loop_index, pan));
+    }
+
+    fn get_loop_pan(&self, loop_index : usize) -> u8 {
+        self.loop_pans[loop_index]
+    }
+
+    fn set_loop_reverse(&mut self, loop_index : usize, time : usize, reverse : bool) {
+        self.loop_reverse[loop_index] = reverse;
+        self.send(time, Command::SetReverse(loop_index, reverse));
+    }
+
+    fn is_loop_reversed(&self, loop_index : usize) -> bool {
+        self.loop_reverse[loop_index]
+    }
+
+    fn set_loop_speed(&mut self, loop_index : usize, time : usize, speed : LoopSpeed) {
+        self.loop_speeds[loop_index] = speed;
+        self.send(time, Command::SetSpeed(loop_index, speed));
+    }
+
+    fn get_loop_speed(&self, loop_index : usize) -> LoopSpeed {
+        self.loop_speeds[loop_index]
+    }
+
+    fn set_loop_program(&mut self, loop_index : usize, time : usize, program : u8) {
+        self.send(time, Command::SetProgram(loop_index, program));
+    }
+
+    fn set_effect_param(&mut self, target : usize, time : usize, effect : Effect, param : usize, value : u8) {
+        if target < self.effect_params.len() && param < EFFECT_PARAM_COUNT {
+            self.effect_params[target][effect as usize][param] = value;
+            if param == effects::MIX && value > 0 && effects::uses_memory(effect) {
+                self.allocate_effect_memory(target);
+            }
+            self.send(time, Command::SetEffectParam(target, effect, param, value));
+        }
+    }
+
+    fn get_effect_param(&self, target : usize, effect : Effect, param : usize) -> u8 {
+        self.effect_params[target][effect as usize][param]
+    }
+
+    fn set_tempo(&mut self, time : usize, tempo : u32) {
+        self.send(time, Command::SetTempo(tempo));
+    }
+
+    fn set_monitoring(&mut self, time : usize, mode : MonitorMode) {
+        self.monitoring = Some(mode);
+        self.send(time, Command::SetMonitoring(mode));
+    }
+
+    fn save_scene(&mut self, scene_index : usize, _time : usize) {
+        if scene_index < SCENE_COUNT {
+            self.scenes[scene_index] = Some(Scene::new(&self.loop_states, &self.loop_gains));
+        }
+    }
+
+    fn recall_scene(&mut self, scene_index : usize, time : usize) {
+        // The audio engine applies all the changes of the scene at the beginning of the master loop.
+        // The loop states are updated when the audio engine publishes them.
+        if let Some(&Some(scene)) = self.scenes.get(scene_index) {
+            for (i, gain) in self.loop_gains.iter_mut().enumerate() {
+                if scene.states[i].is_some() {
+                    *gain = scene.gains[i];
+                }
+            }
+            self.send(time, Command::RecallScene(scene));
+        }
+    }
+}
+
+// The audio engine side of the command queue.
+pub struct CommandReceiver {
+    commands : Consumer<TimedCommand>,
+    pending  : Option<TimedCommand>,
+    memory   : Consumer<(usize, EffectMemory)>
+}
+
+impl CommandReceiver {
+    fn new(commands : Consumer<TimedCommand>, memory : Consumer<(usize, EffectMemory)>) -> Self {
+        Self {
+            commands : commands,
+            pending  : None,
+            memory   : memory
+        }
+    }
+
+    // Get the memory of the effects of a target, sent before the command that enables them.
+    pub fn next_effect_memory(&mut self) -> Option<(usize, EffectMemory)> {
+        self.memory.pop()
+    }
+
+    // Get the next command to apply in the current cycle, with its time relative to the cycle start.
+    // Commands are sent at most one period ahead of the next cycle.
+    // Later times are those of late commands, which are applied at the beginning of the cycle.
+    pub fn next(&mut self, frame_time : u32, n_frames : u32) -> Option<(usize, Command)> {
+        let c = self.pending.take().or_else(|| self.commands.pop())?;
+        let offset = c.time.wrapping_sub(frame_time);
+        if offset < n_frames {
+            return Some((offset as usize, c.command))
+        }
+        if offset < 2 * n_frames {
+            self.pending = Some(c);
+            return None
+        }
+        Some((0, c.command))
+    }
+}
@@ -0,0 +1,370 @@
+
+use std::f32::consts::PI;
+use midi314::{Effect, DEFAULT_EFFECT_PARAMS, EFFECT_COUNT, EFFECT_PARAM_COUNT};
+use config::seconds_to_samples;
+use ramp::Ramp;
+use mix_ramp_length;
+
+// The maximum delay time, in seconds.
+const MAX_DELAY : f32 = 2.0;
+
+// The index of the mix parameter of every effect. A mix of 0 bypasses the effect.
+pub const MIX : usize = 2;
+
+// The duration of delay time changes, in seconds.
+const DELAY_RAMP_DURATION : f32 = 0.1;
+
+// The tunings of the Freeverb reverb, in samples at 44100 Hz.
+const COMB_TUNINGS    : [usize ; 8] = [1116, 1188, 1277, 1356, 1422, 1491, 1557, 1617];
+const ALLPASS_TUNINGS : [usize ; 4] = [556, 441, 341, 225];
+const STEREO_SPREAD   : usize = 23;
+const REVERB_INPUT_GAIN : f32 = 0.015;
+const REVERB_WET_GAIN   : f32 = 3.0;
+
+// A value from 0 to 1, from a CC value.
+fn unit(n : u8) -> f32 {
+    n as f32 / 127.0
+}
+
+// A value on an exponential scale from min to max, from a CC value.
+fn exp_scale(n : u8, min : f32, max : f32) -> f32 {
+    min * (max / min).powf(unit(n))
+}
+
+// A low-pass state-variable filter.
+#[derive(Clone)]
+struct Filter {
+    ic1 : f32,
+    ic2 : f32
+}
+
+impl Filter {
+    fn process(&mut self, x : f32, a : &[f32 ; 3]) -> f32 {
+        let v3 = x - self.ic2;
+        let v1 = a[0] * self.ic1 + a[1] * v3;
+        let v2 = self.ic2 + a[1] * self.ic1 + a[2] * v3;
+        self.ic1 = 2.0 * v1 - self.ic1;
+        self.ic2 = 2.0 * v2 - self.ic2;
+        v2
+    }
+}
+
+// A bit depth and sample rate reducer.
+#[derive(Clone)]
+struct Bitcrusher {
+    held  : f32,
+    count : usize
+}
+
+impl Bitcrusher {
+    fn process(&mut self, x : f32, levels : f32, hold : usize) -> f32 {
+        if self.count == 0 {
+            self.held = (x * levels).round() / levels;
+        }
+        self.count = (self.count + 1) % hold;
+        self.held
+    }
+}
+
+// A delay line with feedback.
+#[derive(Clone)]
+struct Delay {
+    buffer   : Vec<f32>,
+    position : usize
+}
+
+impl Delay {
+    fn process(&mut self, x : f32, time : f32, feedback : f32) -> f32 {
+        // Read between two samples, so that delay time changes are smooth.
+        let n = self.buffer.len();
+        let position = (self.position + n) as f32 - time;
+        let index = position as usize;
+        let frac  = position - position.floor();
+        let a = self.buffer[index % n];
+        let b = self.buffer[(index + 1) % n];
+        let y = a + frac * (b - a);
+        self.buffer[self.position] = x + feedback * y;
+        self.position = (self.position + 1) % n;
+        y
+    }
+
+    fn clear(&mut self) {
+        for x in &mut self.buffer {
+            *x = 0.0;
+        }
+    }
+}
+
+// A feedback comb filter with a low-pass filter in the feedback path.
+#[derive(Clone)]
+struct Comb {
+    buffer   : Vec<f32>,
+    position : usize,
+    store    : f32
+}
+
+// A Schroeder all-pass filter.
+#[derive(Clone)]
+struct AllPass {
+    buffer   : Vec<f32>,
+    position : usize
+}
+
+// A Freeverb reverb: parallel comb filters followed by all-pass filters in series.
+#[derive(Clone)]
+struct Reverb {
+    combs     : Vec<Comb>,
+    allpasses : Vec<AllPass>
+}
+
+impl Reverb {
+    fn new(channel : usize, sample_rate : usize) -> Self {
+        // Channels have slightly different tunings, for a wider stereo image.
+        let spread = if channel % 2 == 1 { STEREO_SPREAD } else { 0 };
+        let length = |l : usize| (l + spread) * sample_rate / 44100;
+        Self {
+            combs     : COMB_TUNINGS.iter().map(|&l| Comb {
+                buffer   : vec![0.0 ; length(l)],
+                position : 0,
+                store    : 0.0
+            }).collect(),
+            allpasses : ALLPASS_TUNINGS.iter().map(|&l| AllPass {
+                buffer   : vec![0.0 ; length(l)],
+                position : 0
+            }).collect()
+        }
+    }
+
+    fn process(&mut self, x : f32, feedback : f32, damping : f32) -> f32 {
+        let input = x * REVERB_INPUT_GAIN;
+        let mut y = 0.0;
+        for c in &mut self.combs {
+            let out = c.buffer[c.position];
+            c.store = out * (1.0 - damping) + c.store * damping;
+            c.buffer[c.position] = input + c.store * feedback;
+            c.position = (c.position + 1) % c.buffer.len();
+            y += out;
+        }
+        for a in &mut self.allpasses {
+            let out = a.buffer[a.position];
+            a.buffer[a.position] = y + out * 0.5;
+            a.position = (a.position + 1) % a.buffer.len();
+            y = out - y;
+        }
+        y
+    }
+
+    fn clear(&mut self) {
+        for c in &mut self.combs {
+            c.store = 0.0;
+            for x in &mut c.buffer {
+                *x = 0.0;
+            }
+        }
+        for a in &mut self.allpasses {
+            for x in &mut a.buffer {
+                *x = 0.0;
+            }
+        }
+    }
+}
+
+// The memory of the delays and reverbs of an effect chain.
+// It is allocated on the control side when one of these effects is enabled for the first time,
+// because it holds the maximum delay time for each channel.
+pub struct EffectMemory {
+    delays  : Vec<Delay>,
+    reverbs : Vec<Reverb>
+}
+
+impl EffectMemory {
+    pub fn new(channels : usize, sample_rate : usize) -> Self {
+        let delay_length = seconds_to_samples(MAX_DELAY, sample_rate) + 2;
+        Self {
+            delays  : vec![Delay { buffer : vec![0.0 ; delay_length], position : 0 } ; channels],
+            reverbs : (0 .. channels).map(|c| Reverb::new(c, sample_rate)).collect()
+        }
+    }
+}
+
+// Check whether an effect needs the memory of the chain.
+pub fn uses_memory(effect : Effect) -> bool {
+    effect == Effect::Delay || effect == Effect::Reverb
+}
+
+// The effects applied to a loop or to the master output, in a fixed order:
+// filter, bitcrusher, tremolo, delay and reverb.
+// All parameters are CC values, and an effect with a mix of 0 is bypassed.
+// The delay and the reverb are bypassed until the chain has received its memory.
+#[derive(Clone)]
+pub struct EffectChain {
+    params      : [[u8 ; EFFECT_PARAM_COUNT] ; EFFECT_COUNT],
+    mixes       : [Ramp ; EFFECT_COUNT],
+    sample_rate : usize,
+    // The coefficients of the filter.
+    filter_coefs  : [f32 ; 3],
+    filters       : Vec<Filter>,
+    crushers      : Vec<Bitcrusher>,
+    tremolo_phase : f32,
+    delay_time    : Ramp,
+    delays        : Vec<Delay>,
+    reverbs       : Vec<Reverb>
+}
+
+impl EffectChain {
+    pub fn new(channels : usize, sample_rate : usize) -> Self {
+        let mut chain = Self {
+            params        : [DEFAULT_EFFECT_PARAMS ; EFFECT_COUNT],
+            mixes         : [Ramp::new(0.0) ; EFFECT_COUNT],
+            sample_rate   : sample_rate,
+            filter_coefs  : [0.0 ; 3],
+            filters       : vec![Filter { ic1 : 0.0, ic2 : 0.0 } ; channels],
+            crushers      : vec![Bitcrusher { held : 0.0, count : 0 } ; channels],
+            tremolo_phase : 0.0,
+            delay_time    : Ramp::new(0.0),
+            delays        : Vec::new(),
+            reverbs       : Vec::new()
+        };
+        chain.update_filter();
+        chain.delay_time.reset(chain.delay_samples());
+        chain
+    }
+
+    pub fn has_memory(&self) -> bool {
+        !self.delays.is_empty()
+    }
+
+    // Give the chain the memory of its delays and reverbs, allocated at the current sample rate.
+    pub fn set_memory(&mut self, memory : EffectMemory) {
+        self.delays  = memory.delays;
+        self.reverbs = memory.reverbs;
+        self.delay_time.reset(self.delay_samples());
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate : usize) {
+        self.sample_rate = sample_rate;
+        self.update_filter();
+        self.delay_time.reset(self.delay_samples());
+    }
+
+    // Set a parameter of an effect to a CC value.
+    pub fn set_param(&mut self, effect : Effect, param : usize, value : u8) {
+        let e = effect as usize;
+        if param >= EFFECT_PARAM_COUNT {
+            return
+        }
+        self.params[e][param] = value;
+        if param == MIX {
+            // The state of an effect is cleared when it is enabled,
+            // so that the tails of the delay and reverb are not heard again.
+            if self.mixes[e].value() == 0.0 && self.mixes[e].target() == 0.0 {
+                self.clear(effect);
+            }
+            self.mixes[e].set(unit(value), mix_ramp_length(self.sample_rate));
+        }
+        match effect {
+            Effect::Filter => self.update_filter(),
+            Effect::Delay  => {
+                let time = self.delay_samples();
+                let length = seconds_to_samples(DELAY_RAMP_DURATION, self.sample_rate);
+                self.delay_time.set(time, length);
+            },
+            _ => {}
+        }
+    }
+
+    fn param(&self, effect : Effect, param : usize) -> u8 {
+        self.params[effect as usize][param]
+    }
+
+    fn update_filter(&mut self) {
+        // The cutoff frequency goes from 20 Hz to 20 kHz, and the resonance from none to strong.
+        let cutoff = exp_scale(self.param(Effect::Filter, 0), 20.0, 20000.0).min(0.45 * self.sample_rate as f32);
+        let k  = 2.0 - 1.96 * unit(self.param(Effect::Filter, 1));
+        let g  = (PI * cutoff / self.sample_rate as f32).tan();
+        let a1 = 1.0 / (1.0 + g * (g + k));
+        self.filter_coefs = [a1, g * a1, g * g * a1];
+    }
+
+    fn delay_samples(&self) -> f32 {
+        // The delay time goes from 10 ms to the maximum delay.
+        let seconds = exp_scale(self.param(Effect::Delay, 0), 0.01, MAX_DELAY);
+        let max = self.delays.first().map_or(0, |d| d.buffer.len() - 2) as f32;
+        (seconds * self.sample_rate as f32).min(max)
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.mixes.iter().any(|m| m.value() > 0.0 || m.target() > 0.0)
+    }
+
+    fn clear(&mut self, effect : Effect) {
+        match effect {
+            Effect::Filter     => for f in &mut self.filters {
+                *f = Filter { ic1 : 0.0, ic2 : 0.0 };
+            },
+            Effect::Bitcrusher => for c in &mut self.crushers {
+                *c = Bitcrusher { held : 0.0, count : 0 };
+            },
+            Effect::Tremolo    => self.tremolo_phase = 0.0,
+            Effect::Delay      => for d in &mut self.delays {
+                d.clear();
+            },
+            Effect::Reverb     => for r in &mut self.reverbs {
+                r.clear();
+            }
+        }
+    }
+
+    // Apply the effects to the given buffers, in place.
+    pub fn process(&mut self, buffers : &mut [&mut [f32]]) {
+        if !self.is_active() {
+            return
+        }
+
+        let levels   = 2.0f32.powf(15.0 - 15.0 * unit(self.param(Effect::Bitcrusher, 0)));
+        let hold     = 1 + self.param(Effect::Bitcrusher, 1) as usize * 31 / 127;
+        let rate     = exp_scale(self.param(Effect::Tremolo, 0), 0.5, 20.0) / self.sample_rate as f32;
+        let shape    = unit(self.param(Effect::Tremolo, 1));
+        let feedback = 0.95 * unit(self.param(Effect::Delay, 1));
+        let room     = 0.7 + 0.28 * unit(self.param(Effect::Reverb, 0));
+        let damping  = 0.4 * unit(self.param(Effect::Reverb, 1));
+
+        let memory = self.has_memory();
+        let len = buffers.first().map_or(0, |b| b.len());
+        for k in 0 .. len {
+            let filter_mix  = self.mixes[Effect::Filter as usize].next();
+            let crusher_mix = self.mixes[Effect::Bitcrusher as usize].next();
+            let depth       = self.mixes[Effect::Tremolo as usize].next();
+            let delay_mix   = self.mixes[Effect::Delay as usize].next();
+            let reverb_mix  = self.mixes[Effect::Reverb as usize].next();
+            let delay_time  = self.delay_time.next();
+
+            // The tremolo shape goes from a sine to a square wave.
+            let sine   = (2.0 * PI * self.tremolo_phase).sin();
+            let square = if sine >= 0.0 { 1.0 } else { -1.0 };
+            let lfo    = sine + shape * (square - sine);
+            let tremolo_gain = 1.0 - depth * (0.5 + 0.5 * lfo);
+            self.tremolo_phase = (self.tremolo_phase + rate) % 1.0;
+
+            for (c, buffer) in buffers.iter_mut().enumerate() {
+                let mut x = buffer[k];
+                if filter_mix > 0.0 {
+                    let y = self.filters[c].process(x, &self.filter_coefs);
+                    x += filter_mix * (y - x);
+                }
+                if crusher_mix > 0.0 {
+                    let y = self.crushers[c].process(x, levels, hold);
+                    x += crusher_mix * (y - x);
+                }
+                x *= tremolo_gain;
+                if delay_mix > 0.0 && memory {
+                    x += delay_mix * self.delays[c].process(x, delay_time, feedback);
+                }
+                if reverb_mix > 0.0 && memory {
+                    x += reverb_mix * REVERB_WET_GAIN * self.reverbs[c].process(x, room, damping);
+                }
+                buffer[k] = x;
+            }
+        }
+    }
+}
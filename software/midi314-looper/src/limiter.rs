@@ -0,0 +1,274 @@
+
+use std::mem;
+use config::seconds_to_samples;
+use ramp::Ramp;
+
+// The lookahead of the limiter, in seconds.
+const LOOKAHEAD : f32 = 0.0015;
+
+// The time constant of the release of the limiter, in seconds.
+const RELEASE : f32 = 0.05;
+
+// The duration of the gain changes of the automatic gain compensation, in seconds.
+const AUTO_GAIN_DURATION : f32 = 0.05;
+
+// The soft clipper is linear up to this fraction of the ceiling.
+const SOFT_CLIP_KNEE : f32 = 0.5;
+
+// How the master output is kept below the ceiling.
+#[derive(Clone, Copy, PartialEq)]
+pub enum LimiterMode {
+    // Reduce the gain smoothly before the peaks, with a short delay of the output.
+    Lookahead,
+    // Bend the peaks above a knee towards the ceiling, without delay.
+    SoftClip,
+    Off
+}
+
+// The gain stage of the master output.
+// With automatic gain compensation, the gain is divided by the square root of the number of playing loops.
+pub struct Limiter {
+    mode      : LimiterMode,
+    ceiling   : f32,
+    auto_gain : bool,
+    gain      : Ramp,
+    playing   : usize,
+    sample_rate : usize,
+    // The delayed output and the target gains in the lookahead window.
+    delay     : Vec<Vec<f32>>,
+    targets   : Vec<f32>,
+    // The gains after the release, averaged over the lookahead window.
+    released  : Vec<f32>,
+    sum       : f32,
+    position  : usize,
+    release   : f32,
+    release_coef : f32
+}
+
+impl Limiter {
+    pub fn new(channels : usize, sample_rate : usize, mode : LimiterMode, ceiling : f32, auto_gain : bool) -> Self {
+        let lookahead = if mode == LimiterMode::Lookahead { seconds_to_samples(LOOKAHEAD, sample_rate).max(2) } else { 1 };
+        let mut limiter = Self {
+            mode      : mode,
+            ceiling   : ceiling,
+            auto_gain : auto_gain,
+            gain      : Ramp::new(1.0),
+            playing   : 0,
+            sample_rate : sample_rate,
+            delay     : vec![vec![0.0 ; lookahead] ; channels],
+            targets   : vec![1.0 ; lookahead],
+            released  : vec![1.0 ; lookahead],
+            sum       : lookahead as f32,
+            position  : 0,
+            release   : 1.0,
+            release_coef : 0.0
+        };
+        limiter.set_sample_rate(sample_rate);
+        limiter
+    }
+
+    // The delay of the output, in samples.
+    pub fn latency(&self) -> usize {
+        self.targets.len() - 1
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate : usize) {
+        self.sample_rate  = sample_rate;
+        self.release_coef = 1.0 - (-1.0 / (RELEASE * sample_rate as f32)).exp();
+    }
+
+    // Process the master output in place, with the number of audio loops that are playing.
+    // Return true if the output exceeded full scale before the limiter.
+    pub fn process(&mut self, outputs : &mut [&mut [f32]], playing : usize) -> bool {
+        if self.auto_gain && playing != self.playing {
+            self.playing = playing;
+            let gain = 1.0 / (playing.max(1) as f32).sqrt();
+            self.gain.set(gain, seconds_to_samples(AUTO_GAIN_DURATION, self.sample_rate));
+        }
+
+        let len = outputs.first().map_or(0, |o| o.len());
+        let mut clipped = false;
+        for k in 0 .. len {
+            let gain = self.gain.next();
+            let mut peak = 0.0f32;
+            for output in outputs.iter_mut() {
+                output[k] *= gain;
+                peak = peak.max(output[k].abs());
+            }
+            clipped = clipped || peak > 1.0;
+
+            match self.mode {
+                LimiterMode::Lookahead => self.limit(outputs, k, peak),
+                LimiterMode::SoftClip  => for output in outputs.iter_mut() {
+                    output[k] = soft_clip(output[k], self.ceiling);
+                },
+                LimiterMode::Off       => {}
+            }
+        }
+
+        // Avoid the accumulation of rounding errors in the sum of the gains.
+        self.sum = self.released.iter().sum();
+        clipped
+    }
+
+    fn limit(&mut self, outputs : &mut [&mut [f32]], k : usize, peak : f32) {
+        // The gain applied to a sample is the average of the gains computed over the next samples,
+        // and each of these gains is the minimum of the target gains over the lookahead window.
+        // The gain reaches the target of a peak smoothly, and never exceeds it.
+        let n = self.targets.len();
+        self.targets[self.position] = if peak > self.ceiling { self.ceiling / peak } else { 1.0 };
+        let hold = self.targets.iter().cloned().fold(1.0, f32::min);
+        self.release = if hold < self.release { hold } else { self.release + (hold - self.release) * self.release_coef };
+        self.sum += self.release - self.released[self.position];
+        self.released[self.position] = self.release;
+        let gain = self.sum / n as f32;
+
+        // The output is delayed by the lookahead minus one sample.
+        let next = (self.position + 1) % n;
+        for (output, delay) in outputs.iter_mut().zip(self.delay.iter_mut()) {
+            delay[self.position] = output[k];
+            output[k] = (delay[next] * gain).max(-self.ceiling).min(self.ceiling);
+        }
+        self.position = next;
+    }
+}
+
+// Delay lines that keep other outputs aligned with the master output, which is delayed by the limiter.
+pub struct LatencyCompensation {
+    lines    : Vec<Vec<f32>>,
+    position : usize
+}
+
+impl LatencyCompensation {
+    pub fn new(count : usize, latency : usize) -> Self {
+        Self {
+            lines    : vec![vec![0.0 ; latency] ; count],
+            position : 0
+        }
+    }
+
+    // Delay a buffer of the current period in the given line.
+    pub fn process(&mut self, line : usize, buffer : &mut [f32]) {
+        let delay = &mut self.lines[line];
+        let n = delay.len();
+        if n == 0 {
+            return
+        }
+        let mut p = self.position;
+        for x in buffer.iter_mut() {
+            mem::swap(x, &mut delay[p]);
+            p = (p + 1) % n;
+        }
+    }
+
+    // Move to the next period, after all the lines have been processed.
+    pub fn advance(&mut self, len : usize) {
+        if let Some(n) = self.lines.first().map(|l| l.len()) {
+            if n > 0 {
+                self.position = (self.position + len) % n;
+            }
+        }
+    }
+}
+
+fn soft_clip(x : f32, ceiling : f32) -> f32 {
+    let knee = SOFT_CLIP_KNEE * ceiling;
+    let a = x.abs();
+    if a <= knee {
+        return x
+    }
+    let y = knee + (ceiling - knee) * ((a - knee) / (ceiling - knee)).tanh();
+    y.copysign(x)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{soft_clip, LatencyCompensation, Limiter, LimiterMode, SOFT_CLIP_KNEE};
+
+    const SAMPLE_RATE : usize = 48000;
+
+    // Process a mono signal in periods of 64 samples.
+    fn limit(limiter : &mut Limiter, input : &[f32]) -> (Vec<f32>, bool) {
+        let mut output  = input.to_vec();
+        let mut clipped = false;
+        for period in output.chunks_mut(64) {
+            clipped = limiter.process(&mut [period], 1) || clipped;
+        }
+        (output, clipped)
+    }
+
+    fn sine(amplitude : f32, length : usize) -> Vec<f32> {
+        (0 .. length).map(|k| amplitude * (k as f32 * 0.05).sin()).collect()
+    }
+
+    #[test]
+    fn lookahead_ceiling() {
+        let ceiling = 0.5;
+        let mut limiter = Limiter::new(1, SAMPLE_RATE, LimiterMode::Lookahead, ceiling, false);
+        let latency = limiter.latency();
+        assert_eq!(latency, 71);
+
+        // A quiet signal is only delayed.
+        let quiet = sine(0.25, 2000);
+        let (output, clipped) = limit(&mut limiter, &quiet);
+        assert!(!clipped);
+        assert!(output[..latency].iter().all(|&y| y == 0.0));
+        for (y, x) in output[latency ..].iter().zip(quiet.iter()) {
+            assert!((y - x).abs() < 1.0e-5);
+        }
+
+        // A loud signal never exceeds the ceiling, and is reduced before its first peak.
+        let loud = sine(2.0, 20000);
+        let (output, clipped) = limit(&mut limiter, &loud);
+        assert!(clipped);
+        assert!(output.iter().all(|y| y.abs() <= ceiling));
+        assert!(output.iter().skip(latency + 10000).any(|y| y.abs() > 0.99 * ceiling));
+    }
+
+    #[test]
+    fn auto_gain() {
+        let mut limiter = Limiter::new(1, SAMPLE_RATE, LimiterMode::Off, 1.0, true);
+        let mut output = vec![1.0 ; 4800];
+        limiter.process(&mut [&mut output[..]], 4);
+        // The gain reaches 1 / sqrt(4) after 50 ms.
+        assert!((output[1199] - 0.75).abs() < 1.0e-3);
+        assert!(output[2400 ..].iter().all(|&y| y == 0.5));
+    }
+
+    #[test]
+    fn soft_clip_knee() {
+        let ceiling = 0.8;
+        let knee = SOFT_CLIP_KNEE * ceiling;
+        // The soft clipper is linear up to the knee.
+        for &x in &[0.0, 0.1, -0.2, knee, -knee] {
+            assert_eq!(soft_clip(x, ceiling), x);
+        }
+        // Above the knee, it is continuous, increasing, symmetric, and below the ceiling.
+        assert!((soft_clip(knee + 1.0e-4, ceiling) - (knee + 1.0e-4)).abs() < 1.0e-6);
+        let mut last = knee;
+        for k in 1 .. 1000 {
+            let x = knee + k as f32 * 0.01;
+            let y = soft_clip(x, ceiling);
+            assert!(y >= last && y <= ceiling);
+            assert_eq!(soft_clip(-x, ceiling), -y);
+            last = y;
+        }
+        assert!(last > 0.999 * ceiling);
+    }
+
+    #[test]
+    fn latency_compensation() {
+        let mut delay = LatencyCompensation::new(2, 5);
+        let mut outputs = Vec::new();
+        for p in 0 .. 4 {
+            let mut a : Vec<f32> = (0 .. 3).map(|k| (p * 3 + k + 1) as f32).collect();
+            let mut b = a.clone();
+            delay.process(0, &mut a);
+            delay.process(1, &mut b);
+            delay.advance(3);
+            assert_eq!(a, b);
+            outputs.extend(a);
+        }
+        assert_eq!(outputs, vec![0.0, 0.0, 0.0, 0.0, 0.0, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0]);
+    }
+}
@@ -1,136 +1,873 @@
 
+extern crate getopts;
+extern crate hound;
 extern crate jack;
+extern crate jack_sys;
 extern crate midi314;
+extern crate ringbuf;
 
-use std::{thread, time};
-use midi314::{Keyboard, LoopManager, LoopState};
+mod alloc_check;
+mod calibrate;
+mod clock;
+mod config;
+mod control;
+mod effects;
+mod limiter;
+mod meter;
+mod midi_loop;
+mod notifications;
+mod offline;
+mod osc;
+mod pool;
+mod pre_roll;
+mod ramp;
+mod remote;
+mod scene;
+mod session;
+mod transport;
+
+use std::{cmp, mem, process, thread, time};
+use std::path::Path;
+use std::sync::Arc;
+use ringbuf::RingBuffer;
+use midi314::{Keyboard, LoopManager, LoopSpeed, LoopState, LooperState, MonitorMode, CLIP_COUNT_CC, LOOP_GAIN_CC, LOOP_MODE_CC, LOOP_PAN_CC, LOOP_POSITION_CC, LOOP_STATE_CC, LOOPER_STATE_CC, XRUN_COUNT_CC};
+use alloc_check::CheckedAllocator;
+use clock::{ClockFollower, ClockMode, MidiClock};
+use config::{Config, MAX_CHANNELS, Timing, db_to_amplitude, db_to_power, ms_to_samples, seconds_to_samples};
+use control::{Command, CommandReceiver, Controller, MidiEvent, DEFAULT_GAIN, DEFAULT_PAN, LOOP_COUNT, QUEUE_CAPACITY};
+use effects::EffectChain;
+use limiter::{LatencyCompensation, Limiter};
+use meter::{Levels, Meters};
+use midi_loop::{MidiBuffers, MidiTrack};
+use notifications::{Notifications, ServerStatus};
+use pool::{ChunkAllocator, ChunkPool, LoopBuffer};
+use pre_roll::PreRoll;
+use ramp::Ramp;
+use remote::OscServer;
+use scene::Scene;
+use session::Session;
+use transport::{Timebase, TransportMode};
+
+#[global_allocator]
+static ALLOCATOR : CheckedAllocator = CheckedAllocator;
+
+// The duration of gain and pan changes, in seconds.
+const MIX_RAMP_DURATION : f32 = 0.01;
+
+// The length of the grains of time-stretched loops, in samples.
+const GRAIN_LENGTH : usize = 2048;
+
+// The maximum number of events sent to the MIDI output in each cycle.
+const MAX_FEEDBACK_EVENTS : usize = 64;
+
+// A copy of the contents of a loop, kept to undo a recording.
+#[derive(Clone)]
+struct Layer {
+    length : usize,
+    count  : usize,
+    buffer : LoopBuffer
+}
+
+impl Layer {
+    fn new(l : usize) -> Self {
+        Self {
+            length : 0,
+            count  : 0,
+            buffer : LoopBuffer::new(l)
+        }
+    }
+}
+
+// The position of the master loop in the loop buffers.
+// The end of the master loop is not known until the first loop has been recorded.
+#[derive(Clone, Copy)]
+struct MasterLoop {
+    from : usize,
+    to   : usize
+}
+
+// The buffers where the loops are played in each period, before they are mixed into the outputs.
+// They are allocated on the control side for the buffer size, and replaced when it changes.
+struct PeriodBuffers {
+    scratch      : Vec<Vec<f32>>,
+    // When each loop has its own output ports, the signal of each loop and of the dry input in the current period.
+    loop_outputs : Vec<Vec<Vec<f32>>>,
+    dry_output   : Vec<Vec<f32>>
+}
+
+impl PeriodBuffers {
+    fn new(config : &Config, n_loops : usize, length : usize) -> Self {
+        let n_outputs = if config.multi_output { n_loops } else { 0 };
+        let n_dry     = if config.multi_output { config.channels } else { 0 };
+        Self {
+            scratch      : vec![vec![0.0 ; length] ; config.channels],
+            loop_outputs : vec![vec![vec![0.0 ; length] ; config.channels] ; n_outputs],
+            dry_output   : vec![vec![0.0 ; length] ; n_dry]
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.scratch[0].len()
+    }
+}
+
+// The direction and speed of the playback of a loop.
+#[derive(Clone, Copy, PartialEq)]
+struct PlaybackMode {
+    reverse : bool,
+    speed   : LoopSpeed
+}
 
 #[derive(Clone)]
 struct Loop {
     state : LoopState,
     state_prev : LoopState,
     transition_time : usize,
-    samples_1 : Vec<f32>,
-    samples_2 : Vec<f32>
+    length : usize,
+    count : usize,
+    master_length : usize,
+    cursor : usize,
+    turn : bool,
+    buffer : LoopBuffer,
+    gain : u8,
+    pan : u8,
+    gains : Vec<Ramp>,
+    fade : Ramp,
+    mode : PlaybackMode,
+    mode_prev : PlaybackMode,
+    mode_fade : Ramp,
+    fade_in_length : usize,
+    fade_out_length : usize,
+    crossfade_length : usize,
+    mix_ramp_length : usize,
+    crossfade : usize,
+    crossfade_position : usize,
+    record_start : usize,
+    record_end : usize,
+    record_skip : usize,
+    latency : usize,
+    // The latency when the recording started, used until the end of the recording is complete.
+    record_latency : usize,
+    undo_layers : Vec<Layer>,
+    redo_layers : Vec<Layer>,
+    free_layers : Vec<Layer>,
+    // The layers to redo (positive) or to undo (negative) at the given time in the current period.
+    layer_steps : isize,
+    layer_time  : usize,
+    effects : EffectChain,
+    // The samples played while the previous recording fades out, when recording again.
+    tail : Vec<Vec<f32>>,
+    tail_length : usize,
+    tail_position : usize,
+    // The events of a MIDI loop, which has no audio contents.
+    midi : Option<MidiTrack>
 }
 
 impl Loop {
-    fn new(l : usize) -> Self {
+    fn new(channels : usize, l : usize, undo_depth : usize, timing : Timing) -> Self {
         Self {
             state           : LoopState::Empty,
             state_prev      : LoopState::Empty,
             transition_time : 0,
-            samples_1       : vec![0.0 ; l],
-            samples_2       : vec![0.0 ; l]
+            length          : 0,
+            count           : 0,
+            master_length   : 0,
+            cursor          : 0,
+            turn            : false,
+            buffer          : LoopBuffer::new(l),
+            gain            : DEFAULT_GAIN,
+            pan             : DEFAULT_PAN,
+            gains           : vec![Ramp::new(1.0) ; channels],
+            fade            : Ramp::new(0.0),
+            mode            : PlaybackMode { reverse : false, speed : LoopSpeed::Normal },
+            mode_prev       : PlaybackMode { reverse : false, speed : LoopSpeed::Normal },
+            mode_fade       : Ramp::new(1.0),
+            fade_in_length  : timing.fade_in,
+            fade_out_length : timing.fade_out,
+            crossfade_length   : timing.crossfade,
+            mix_ramp_length    : mix_ramp_length(timing.sample_rate),
+            crossfade          : 0,
+            crossfade_position : 0,
+            record_start    : 0,
+            record_end      : 0,
+            record_skip     : 0,
+            latency         : timing.latency,
+            record_latency  : timing.latency,
+            undo_layers     : Vec::with_capacity(undo_depth),
+            redo_layers     : Vec::with_capacity(undo_depth),
+            free_layers     : vec![Layer::new(l) ; undo_depth],
+            layer_steps     : 0,
+            layer_time      : 0,
+            effects         : EffectChain::new(channels, timing.sample_rate),
+            tail            : vec![vec![0.0 ; timing.fade_out] ; channels],
+            tail_length     : 0,
+            tail_position   : 0,
+            midi            : None
         }
     }
 
+    fn channels(&self) -> usize {
+        self.gains.len()
+    }
+
     fn set_state(&mut self, time : usize, state : LoopState) {
         self.state = state;
         self.transition_time = time;
     }
 
-    fn run(&mut self, from : usize, to : usize, cursor : usize, in_1 : &[f32], in_2 : &[f32], out_1 : &mut [f32], out_2 : &mut [f32]) {
+    fn set_timing(&mut self, timing : Timing) {
+        self.fade_in_length   = timing.fade_in;
+        self.fade_out_length  = timing.fade_out;
+        self.crossfade_length = timing.crossfade;
+        self.mix_ramp_length  = mix_ramp_length(timing.sample_rate);
+        // A recording keeps the latency that it started with.
+        self.latency          = timing.latency;
+        self.effects.set_sample_rate(timing.sample_rate);
+    }
+
+    fn set_master_length(&mut self, master_length : usize, position : usize) {
+        // The cursor keeps its number of turns of the master loop and the position in the master loop,
+        // so that the loop stays aligned with the master loop.
+        if self.count > 0 {
+            self.cursor = self.cursor / self.master_length * master_length + position;
+        }
+        self.master_length = master_length;
+    }
+
+    // The length of the loop as it is played.
+    // When the tempo changes, a loop keeps its number of master loops, and is time-stretched.
+    fn play_length(&self) -> usize {
+        if self.count > 0 {
+            self.count * self.master_length
+        }
+        else {
+            self.length
+        }
+    }
+
+    fn set_gain(&mut self, time : usize, gain : u8) {
+        self.gain = gain;
+        self.update_mix(time);
+    }
+
+    fn set_pan(&mut self, time : usize, pan : u8) {
+        self.pan = pan;
+        self.update_mix(time);
+    }
+
+    fn set_reverse(&mut self, reverse : bool) {
+        let speed = self.mode.speed;
+        self.set_mode(PlaybackMode { reverse : reverse, speed : speed });
+    }
+
+    fn set_speed(&mut self, speed : LoopSpeed) {
+        let reverse = self.mode.reverse;
+        self.set_mode(PlaybackMode { reverse : reverse, speed : speed });
+    }
+
+    fn set_program(&mut self, program : u8) {
+        if let Some(ref mut track) = self.midi {
+            track.set_program(program);
+        }
+    }
+
+    fn set_mode(&mut self, mode : PlaybackMode) {
+        // Crossfade between the previous and the new mode.
+        if mode != self.mode {
+            self.mode_prev = self.mode;
+            self.mode      = mode;
+            self.mode_fade.reset(0.0);
+            self.mode_fade.set(1.0, self.mix_ramp_length);
+        }
+    }
+
+    // Move the gains of the channels to the current gain and pan, from the given time in the current period.
+    fn update_mix(&mut self, time : usize) {
+        // Map the gain to a quadratic curve.
+        let gain = (self.gain as f32 / 127.0).powi(2);
+
+        // Use a balance law that keeps unity gain on both sides at the center.
+        // Even channels are on the left side, odd channels on the right side.
+        // Pan has no effect on a single channel.
+        let balance = if self.gains.len() > 1 {
+            ((self.pan as f32 - 64.0) / 63.0).clamp(-1.0, 1.0)
+        }
+        else {
+            0.0
+        };
+        for (c, g) in self.gains.iter_mut().enumerate() {
+            let side = if c % 2 == 0 { -balance } else { balance };
+            g.set_at(time, gain * (1.0 + side).min(1.0), self.mix_ramp_length);
+        }
+    }
+
+    fn run(&mut self, master : MasterLoop, cursor : usize, inputs : &[&[f32]], outputs : &mut [&mut [f32]], midi : &mut MidiBuffers, pool : &mut ChunkPool) {
+        let MasterLoop { from, to } = master;
+        let len = inputs[0].len();
+        let mut tail_start = 0;
         match self.state {
-            LoopState::Empty =>
-                if self.state_prev != LoopState::Empty {
-                    // Clear the loop buffer to delete.
-                    let zeros = vec![0.0 ; self.samples_1.len()];
-                    self.samples_1.copy_from_slice(&zeros);
-                    self.samples_2.copy_from_slice(&zeros);
-                },
             LoopState::Recording =>
                 if self.state_prev != LoopState::Recording {
-                    let in_1 = &in_1[self.transition_time..];
-                    let in_2 = &in_2[self.transition_time..];
-                    let cursor = cursor + self.transition_time;
-                    self.record(from, to, cursor, in_1, in_2);
+                    // A deleted loop may still be fading out.
+                    if self.state_prev == LoopState::Empty {
+                        self.clear(pool);
+                    }
+                    // When recording again, play until the transition time,
+                    // and fade out the previous recording while the new one starts.
+                    self.play(from, outputs, midi, 0, self.transition_time);
+                    self.save_tail(from);
+                    tail_start = self.transition_time;
+                    // A MIDI loop releases its notes before recording again.
+                    if let Some(ref mut track) = self.midi {
+                        track.stop(midi, self.transition_time);
+                        track.clear();
+                    }
+                    // When entering the Recording state, start recording from the transition time
+                    // at the current position in the master loop.
+                    self.fade.reset(0.0);
+                    self.start_recording(from, to, cursor + self.transition_time, pool);
+                    self.record(master, inputs, midi, self.transition_time, len, pool);
                 }
                 else {
-                    self.record(from, to, cursor, in_1, in_2)
+                    self.record(master, inputs, midi, 0, len, pool)
                 },
-            LoopState::Playing => {
+            LoopState::Empty if self.state_prev == LoopState::Recording => self.clear(pool),
+            _ => {
+                let mut time = 0;
                 if self.state_prev == LoopState::Recording {
-                    // When transitioning from the Recording to the Playing state,
+                    // When transitioning from the Recording state,
                     // finish recording the beginning of the input buffer (until transition time)
-                    // into the loop buffer at the cursor,
-                    let in_1 = &in_1[0..self.transition_time];
-                    let in_2 = &in_2[0..self.transition_time];
-                    self.record(from, to, cursor, in_1, in_2);
-                }
-                if self.state_prev != LoopState::Playing {
-                    // When entering the Playing state, start playing from the transition time
-                    // into the end of the output buffer.
-                    let out_1 = &mut out_1[self.transition_time..];
-                    let out_2 = &mut out_2[self.transition_time..];
-                    let cursor = cursor + self.transition_time;
-                    self.play(from, to, cursor, out_1, out_2)
+                    // into the loop buffer at the cursor, and choose the length of the loop.
+                    self.record(master, inputs, midi, 0, self.transition_time, pool);
+                    self.stop_recording(from, to);
+                    self.fade.reset(if self.state == LoopState::Playing { 1.0 } else { 0.0 });
+                    time = self.transition_time;
                 }
-                else {
-                    self.play(from, to, cursor, out_1, out_2)
+                else if self.state_prev != self.state {
+                    // When the state changes, play until the transition time
+                    // and start fading in or out.
+                    self.play(from, outputs, midi, 0, self.transition_time);
+                    if self.state == LoopState::Playing {
+                        self.fade.set(1.0, self.fade_in_length);
+                    }
+                    else {
+                        self.fade.set(0.0, self.fade_out_length);
+                    }
+                    time = self.transition_time;
                 }
-            },
-            _ => ()
+
+                // After an undo or a redo, play the current layer until its time,
+                // and crossfade it with the other layer.
+                if self.layer_steps != 0 && self.state != LoopState::Empty {
+                    let t = cmp::max(time, self.layer_time);
+                    self.play(from, outputs, midi, time, t);
+                    self.save_tail(from);
+                    tail_start = t;
+                    self.change_layer();
+                    time = t;
+                }
+
+                // After a recording, keep recording the input into the latency and crossfade regions.
+                if self.state != LoopState::Empty {
+                    self.crossfade(from, inputs, time, len, pool);
+                }
+
+                // While the loop is muted, the cursor keeps moving to stay aligned with the master loop.
+                self.play(from, outputs, midi, time, len);
+
+                // Clear the loop buffer to delete, after fading out.
+                if self.state == LoopState::Empty && self.length > 0 && self.is_silent() {
+                    self.clear(pool);
+                }
+            }
         }
 
-        self.state_prev = self.state;
+        self.play_tail(outputs, tail_start, len);
+        self.state_prev  = self.state;
+        self.layer_steps = 0;
     }
 
-    fn record(&mut self, from : usize, to : usize, cursor : usize, in_1 : &[f32], in_2 : &[f32]) {
-        let length = if to == 0 {
-            self.samples_1.len()
+    // Keep the samples that the loop would play during a fade-out from the current position.
+    fn save_tail(&mut self, from : usize) {
+        self.tail_position = 0;
+        self.tail_length = if self.length == 0 || self.midi.is_some() || self.is_silent() {
+            0
         }
         else {
-            to
+            // The tail has the length of the fade-out at the initial sample rate.
+            cmp::min(self.fade_out_length, self.tail[0].len())
         };
 
-        let mut in_len = in_1.len();
-        let mut in_index = 0;
-        let mut cursor = cursor;
+        let (cursor, turn) = (self.cursor, self.turn);
+        let fade = self.fade.value();
+        for k in 0 .. self.tail_length {
+            let g = fade * (self.tail_length - k) as f32 / self.tail_length as f32;
+            for c in 0 .. self.channels() {
+                let x = self.read(from, self.mode, c);
+                self.tail[c][k] = g * self.gains[c].value() * x;
+            }
+            self.advance();
+        }
+        self.cursor = cursor;
+        self.turn   = turn;
+    }
 
-        while in_len > 0 {
-            let copy_len = if cursor + in_len < length {
-                in_len
+    fn play_tail(&mut self, outputs : &mut [&mut [f32]], start : usize, end : usize) {
+        for k in start .. end {
+            if self.tail_position >= self.tail_length {
+                break
+            }
+            for (output, tail) in outputs.iter_mut().zip(self.tail.iter()) {
+                output[k] += tail[self.tail_position];
+            }
+            self.tail_position += 1;
+        }
+    }
+
+    fn crossfade(&mut self, from : usize, inputs : &[&[f32]], start : usize, end : usize, pool : &mut ChunkPool) {
+        if self.length == 0 || self.midi.is_some() {
+            return
+        }
+
+        // The input is late by the latency: it is written behind the cursor.
+        // Its first samples complete the end of the recording.
+        // Then it is blended into the loop, so that the loop wraps around without discontinuity.
+        let mut cursor = (self.cursor + self.length - self.record_latency % self.length) % self.length;
+        for k in start .. end {
+            if self.crossfade_position >= self.record_latency + self.crossfade {
+                break
+            }
+            let g = if self.crossfade_position < self.record_latency {
+                0.0
             }
             else {
-                length - cursor
+                (self.crossfade_position - self.record_latency) as f32 / self.crossfade as f32
             };
+            let index = from + cursor;
+            for (c, input) in inputs.iter().enumerate() {
+                if let Some(x) = self.buffer.get_mut_or_acquire(pool, c, index) {
+                    *x = g * *x + (1.0 - g) * input[k];
+                }
+            }
+            self.crossfade_position += 1;
+            cursor = (cursor + 1) % self.length;
+
+            // When the end of the recording is complete, fade its boundaries.
+            if self.crossfade_position == self.record_latency {
+                self.fade_boundaries(from);
+            }
+        }
+    }
+
+    fn clear(&mut self, pool : &mut ChunkPool) {
+        // Give the memory of the loop back to the pool.
+        self.buffer.release(pool);
+        self.length = 0;
+        self.count = 0;
+        self.cursor = 0;
+        self.crossfade = 0;
+        self.fade.reset(0.0);
+        self.clear_layers(pool);
+        if let Some(ref mut track) = self.midi {
+            track.clear();
+        }
+    }
+
+    fn is_silent(&self) -> bool {
+        self.fade.value() == 0.0 && self.fade.target() == 0.0
+    }
+
+    // Check whether the input is being written into the loop, including after the end of a recording.
+    fn is_recording(&self) -> bool {
+        self.state == LoopState::Recording ||
+            self.midi.is_none() && self.length > 0 && self.crossfade_position < self.record_latency + self.crossfade
+    }
+
+    fn start_recording(&mut self, from : usize, to : usize, cursor : usize, pool : &mut ChunkPool) {
+        // Keep the previous recording, and start again with an empty buffer.
+        self.save_layer(pool);
+
+        // The recording starts at the current position in the master loop.
+        // The length of the new loop is not known until the recording stops.
+        let offset = cursor.saturating_sub(from);
+        self.cursor = if to > from {
+            offset % (to - from)
+        }
+        else {
+            offset
+        };
+        // When the master loop exists, the input that precedes the recording is skipped.
+        // The first loop is triggered by the input itself, so it does not need to skip anything.
+        self.record_latency = self.latency;
+        self.record_skip  = if to > from { self.record_latency } else { 0 };
+        self.record_start = self.cursor + self.record_skip - self.record_latency;
+        self.length = 0;
+        self.count = 0;
+        self.crossfade = 0;
+        self.crossfade_position = 0;
+    }
+
+    fn prepend(&mut self, from : usize, samples : &[Vec<f32>], pool : &mut ChunkPool) {
+        // The first loop starts with the samples that precede the first note.
+        let mut inputs : [&[f32] ; MAX_CHANNELS] = Default::default();
+        for (i, s) in inputs.iter_mut().zip(samples.iter()) {
+            *i = s;
+        }
+        self.buffer.write(pool, from, &inputs[..samples.len()], 0, samples[0].len());
+        self.record_start = 0;
+    }
+
+    fn stop_recording(&mut self, from : usize, to : usize) {
+        if to <= from {
+            return
+        }
+
+        // The length of the loop is the smallest multiple of the master loop length
+        // that contains the recorded samples, within the capacity of the loop buffer.
+        let master_length = to - from;
+        let max_count = cmp::max(1, (self.buffer.len() - from) / master_length);
+        let count = self.cursor.div_ceil(master_length);
+        self.count = cmp::min(cmp::max(1, count), max_count);
+        self.length = self.count * master_length;
+        self.master_length = master_length;
+
+        // Prepare to crossfade the input that follows the recording
+        // with the beginning of the loop.
+        self.record_end = self.cursor;
+        if let Some(ref mut track) = self.midi {
+            track.stop_recording(self.record_end, self.length);
+        }
+        let recorded = cmp::min(self.record_end - self.record_start, self.length);
+        self.crossfade = cmp::min(self.crossfade_length, recorded / 2);
+        self.crossfade_position = 0;
+
+        // With latency compensation, the end of the recording is not complete yet.
+        if self.record_latency == 0 {
+            self.fade_boundaries(from);
+        }
+        self.cursor %= self.length;
+    }
 
-            let src_1  = &in_1[in_index .. in_index + copy_len];
-            let src_2  = &in_2[in_index .. in_index + copy_len];
-            let dest_1 = &mut self.samples_1[cursor .. cursor + copy_len];
-            let dest_2 = &mut self.samples_2[cursor .. cursor + copy_len];
-            dest_1.copy_from_slice(src_1);
-            dest_2.copy_from_slice(src_2);
+    fn fade_boundaries(&mut self, from : usize) {
+        // Fade in the beginning of the recording and fade out its end,
+        // so that the loop does not click where the recording stopped.
+        let recorded = cmp::min(self.record_end - self.record_start, self.length);
+        let fade_in  = cmp::min(self.fade_in_length,  recorded / 2);
+        // When crossfading, the end of the recording is followed by the crossfade region.
+        let fade_out = if self.crossfade > 0 {
+            0
+        }
+        else {
+            cmp::min(self.fade_out_length, recorded / 2)
+        };
+
+        for c in 0 .. self.channels() {
+            for k in 0 .. fade_in {
+                let index = from + (self.record_start + k) % self.length;
+                if let Some(x) = self.buffer.get_mut(c, index) {
+                    *x *= k as f32 / fade_in as f32;
+                }
+            }
 
-            in_len   -= copy_len;
-            in_index += copy_len;
-            cursor   += copy_len;
-            if cursor == length {
-                cursor = from;
+            for k in 0 .. fade_out {
+                let index = from + (self.record_end + self.length - 1 - k) % self.length;
+                if let Some(x) = self.buffer.get_mut(c, index) {
+                    *x *= k as f32 / fade_out as f32;
+                }
             }
         }
     }
 
-    fn play(&self, from : usize, to : usize, cursor : usize, out_1 : &mut [f32], out_2 : &mut [f32]) {
-        let mut cursor = cursor;
-        for k in 0 .. out_1.len() {
-            if cursor >= to {
-                cursor -= to - from;
+    fn save_layer(&mut self, pool : &mut ChunkPool) {
+        // MIDI loops have no undo levels.
+        if self.length == 0 || self.midi.is_some() {
+            self.buffer.release(pool);
+            return
+        }
+
+        // A new recording cannot be redone after an undo.
+        while let Some(mut layer) = self.redo_layers.pop() {
+            layer.buffer.release(pool);
+            self.free_layers.push(layer);
+        }
+
+        // When all layers are used, reuse the oldest one.
+        let layer = if let Some(layer) = self.free_layers.pop() {
+            Some(layer)
+        }
+        else if !self.undo_layers.is_empty() {
+            let mut layer = self.undo_layers.remove(0);
+            layer.buffer.release(pool);
+            Some(layer)
+        }
+        else {
+            None
+        };
+
+        // The recording is moved to the layer, which leaves an empty buffer in the loop.
+        if let Some(mut layer) = layer {
+            mem::swap(&mut self.buffer, &mut layer.buffer);
+            layer.length = self.length;
+            layer.count  = self.count;
+            self.undo_layers.push(layer);
+        }
+        else {
+            self.buffer.release(pool);
+        }
+    }
+
+    fn swap_layer(&mut self, layer : &mut Layer) {
+        mem::swap(&mut self.length, &mut layer.length);
+        mem::swap(&mut self.count,  &mut layer.count);
+        mem::swap(&mut self.buffer, &mut layer.buffer);
+
+        // All loop lengths are multiples of the master loop length,
+        // so this keeps the loop aligned with the master loop.
+        // A layer recorded at another tempo is stretched to the current master loop length.
+        if self.length > 0 {
+            self.cursor %= self.play_length();
+        }
+    }
+
+    // Undo the last recording at the given time in the current period.
+    // The layers are changed when the loop is played, after its recording is complete.
+    fn undo(&mut self, time : usize) {
+        if self.state == LoopState::Empty || self.is_recording() {
+            return
+        }
+        if self.layer_steps > 0 || self.undo_layers.len() > (-self.layer_steps) as usize {
+            self.layer_steps -= 1;
+            self.layer_time   = time;
+        }
+    }
+
+    fn redo(&mut self, time : usize) {
+        if self.state == LoopState::Empty || self.is_recording() {
+            return
+        }
+        if self.layer_steps < 0 || self.redo_layers.len() > self.layer_steps as usize {
+            self.layer_steps += 1;
+            self.layer_time   = time;
+        }
+    }
+
+    fn change_layer(&mut self) {
+        while self.layer_steps < 0 {
+            if let Some(mut layer) = self.undo_layers.pop() {
+                self.swap_layer(&mut layer);
+                self.redo_layers.push(layer);
             }
-            out_1[k] += self.samples_1[cursor];
-            out_2[k] += self.samples_2[cursor];
-            cursor += 1;
+            self.layer_steps += 1;
         }
+        while self.layer_steps > 0 {
+            if let Some(mut layer) = self.redo_layers.pop() {
+                self.swap_layer(&mut layer);
+                self.undo_layers.push(layer);
+            }
+            self.layer_steps -= 1;
+        }
+
+        // The new layer fades in while the previous one fades out.
+        let fade = self.fade.target();
+        self.fade.reset(0.0);
+        self.fade.set(fade, self.fade_out_length);
     }
-}
 
-#[derive(PartialEq, PartialOrd)]
-enum LooperState {
-    Idle,
-    WaitingFirstNote,
-    RecordingFirstLoop,
-    Running
+    fn clear_layers(&mut self, pool : &mut ChunkPool) {
+        while let Some(mut layer) = self.undo_layers.pop() {
+            layer.buffer.release(pool);
+            self.free_layers.push(layer);
+        }
+        while let Some(mut layer) = self.redo_layers.pop() {
+            layer.buffer.release(pool);
+            self.free_layers.push(layer);
+        }
+    }
+
+    fn capacity(&self, from : usize, to : usize) -> usize {
+        let available = self.buffer.len() - from;
+        if to > from {
+            // Keep the capacity to a multiple of the master loop length.
+            let master_length = to - from;
+            cmp::max(1, available / master_length) * master_length
+        }
+        else {
+            available
+        }
+    }
+
+    fn record(&mut self, master : MasterLoop, inputs : &[&[f32]], midi : &MidiBuffers, start : usize, end : usize, pool : &mut ChunkPool) {
+        // MIDI events are recorded at the cursor:
+        // the latency of the audio input is compensated when they are played.
+        if let Some(ref mut track) = self.midi {
+            track.record(midi, start, end, self.cursor);
+            self.cursor += end - start;
+            return
+        }
+
+        // While recording, the cursor counts the samples since the beginning of the loop.
+        // Recording wraps around at the end of the loop buffer.
+        let capacity = self.capacity(master.from, master.to);
+
+        let mut in_len = end - start;
+        let mut in_index = start;
+
+        while in_len > 0 {
+            // The input is late by the latency: it is written behind the cursor.
+            if self.record_skip > 0 {
+                let skip_len = cmp::min(in_len, self.record_skip);
+                self.record_skip -= skip_len;
+                in_len           -= skip_len;
+                in_index         += skip_len;
+                self.cursor      += skip_len;
+                continue
+            }
+
+            let offset   = (self.cursor - self.record_latency) % capacity;
+            let copy_len = cmp::min(in_len, capacity - offset);
+
+            self.buffer.write(pool, master.from + offset, inputs, in_index, in_index + copy_len);
+
+            in_len      -= copy_len;
+            in_index    += copy_len;
+            self.cursor += copy_len;
+        }
+    }
+
+    fn skip(&mut self, len : usize) {
+        if self.length > 0 {
+            let n = self.play_length();
+            if (self.cursor + len) / n % 2 == 1 {
+                self.turn = !self.turn;
+            }
+            self.cursor = (self.cursor + len) % n;
+        }
+        // Mix changes go on without being heard while the loop is not playing.
+        if len > 0 {
+            for g in &mut self.gains {
+                g.skip(len);
+            }
+            self.mode_fade.finish();
+        }
+    }
+
+    fn position(&self, mode : PlaybackMode, time : f64) -> (f64, f64) {
+        // The position depends only on the time, so that the loop stays aligned with the master loop.
+        // Time is counted over two turns of the cursor: at half speed, the loop is played over two turns.
+        let n = self.play_length() as f64;
+        let (position, rate) = match mode.speed {
+            LoopSpeed::Normal => (time % n,       1.0),
+            LoopSpeed::Half   => (time / 2.0,     0.5),
+            LoopSpeed::Double => (2.0 * time % n, 2.0)
+        };
+        let (position, rate) = if mode.reverse {
+            ((2.0 * n - 1.0 - position) % n, -rate)
+        }
+        else {
+            (position, rate)
+        };
+        // Return the position in the contents of the loop, with the rate at which they are read.
+        (position * self.length as f64 / n, rate)
+    }
+
+    fn sample(&self, from : usize, position : f64, rate : f64, channel : usize) -> f32 {
+        let n = self.length;
+        let position = position.rem_euclid(n as f64);
+        let index = position as usize % n;
+        let frac  = (position - position.floor()) as f32;
+
+        // Interpolate between two samples at half speed and in time-stretched loops.
+        let get    = |k| self.buffer.get(channel, from + (index + k) % n);
+        let interp = |k| {
+            let x = get(k);
+            if frac > 0.0 { x + frac * (get(k + 1) - x) } else { x }
+        };
+        if rate.abs() > 1.0 {
+            // Average two samples at double speed to reduce aliasing.
+            (interp(0) + interp(1)) / 2.0
+        }
+        else {
+            interp(0)
+        }
+    }
+
+    fn read(&self, from : usize, mode : PlaybackMode, channel : usize) -> f32 {
+        let n = self.play_length();
+        let time = (self.cursor + if self.turn { n } else { 0 }) as f64;
+        if n == self.length {
+            let (position, rate) = self.position(mode, time);
+            return self.sample(from, position, rate, channel)
+        }
+
+        // A time-stretched loop is played with two overlapping grains that read the contents
+        // at the rate of the playback mode, so that the pitch is preserved.
+        // Each grain starts at the position given by its start time, and fades in and out with a triangular window.
+        // Grains are aligned with the beginning of the loop, so that there is no discontinuity when it wraps around.
+        let period = 2.0 * n as f64;
+        let hop    = period / (period / (GRAIN_LENGTH / 2) as f64).round().max(1.0);
+        let start  = (time / hop).floor() * hop;
+        let offset = time - start;
+        let (position, rate) = self.position(mode, start);
+        let (previous, _)    = self.position(mode, (start + period - hop) % period);
+        let x = self.sample(from, position + rate * offset, rate, channel);
+        let y = self.sample(from, previous + rate * (offset + hop), rate, channel);
+        let w = (offset / hop) as f32;
+        w * x + (1.0 - w) * y
+    }
+
+    fn play_events(&mut self, midi : &mut MidiBuffers, start : usize, end : usize) {
+        // Events are sent earlier by the latency, so that the synthesizer plays them
+        // at the same time as the audio loops.
+        // MIDI loops follow the tempo, but not the playback modes.
+        let n       = self.play_length();
+        let length  = self.length;
+        let cursor  = (self.cursor + self.latency) % n;
+        let playing = self.fade.target() > 0.0;
+        if let Some(ref mut track) = self.midi {
+            if playing {
+                track.play(midi, start, end, cursor, n, length);
+            }
+            else {
+                track.stop(midi, start);
+            }
+        }
+    }
+
+    fn play(&mut self, from : usize, outputs : &mut [&mut [f32]], midi : &mut MidiBuffers, start : usize, end : usize) {
+        if self.length == 0 {
+            return
+        }
+        if self.midi.is_some() {
+            self.play_events(midi, start, end);
+            self.fade.finish();
+            self.skip(end - start);
+            return
+        }
+        if self.is_silent() {
+            self.skip(end - start);
+            return
+        }
+        for k in start .. end {
+            let fade = self.fade.next();
+            let mode_fade = self.mode_fade.next();
+            for (c, output) in outputs.iter_mut().enumerate() {
+                let mut x = self.read(from, self.mode, c);
+                if mode_fade < 1.0 {
+                    x = mode_fade * x + (1.0 - mode_fade) * self.read(from, self.mode_prev, c);
+                }
+                output[k] += fade * self.gains[c].next() * x;
+            }
+            self.advance();
+        }
+    }
+
+    fn advance(&mut self) {
+        self.cursor += 1;
+        if self.cursor == self.play_length() {
+            self.cursor = 0;
+            self.turn = !self.turn;
+        }
+    }
 }
 
 struct Looper {
@@ -139,18 +876,187 @@ struct Looper {
     from      : usize,
     to        : usize,
     cursor    : usize,
-    threshold : f32
+    turns     : usize,
+    threshold : f32,
+    pre_roll  : PreRoll,
+    pre_roll_pending : bool,
+    trigger_on_note  : bool,
+    note_on   : Option<usize>,
+    latency   : usize,
+    tempo     : u32,
+    tempo_pending : Option<u32>,
+    tempo_follow  : bool,
+    // The length of the master loop at the tempo of the first loop.
+    reference_length : usize,
+    reference_tempo  : u32,
+    // When following the Jack transport, the first loop starts and stops on a bar.
+    next_bar     : Option<usize>,
+    state_at_bar : Option<(usize, LoopState)>,
+    // A scene to recall at the beginning of the master loop.
+    scene_pending : Option<Scene>,
+    // When sending MIDI clock, the clock events of the current period.
+    clock        : Option<MidiClock>,
+    // When following MIDI clock, the first loop stops on a beat.
+    clock_follow : bool,
+    sample_rate  : usize,
+    // When the input is passed through to the outputs, with a gain that fades when it changes.
+    monitoring   : MonitorMode,
+    monitor_gain : Ramp,
+    // The effects of the master output, and the buffers where loops are played before their effects.
+    effects   : EffectChain,
+    buffers   : PeriodBuffers,
+    // The gain stage of the master output, and the number of periods where the output exceeded full scale.
+    // The separate outputs of the loops and the dry input are delayed like the master output.
+    limiter   : Limiter,
+    clips     : usize,
+    output_delay : LatencyCompensation,
+    // The levels of the input, the loops and the master output, and the last levels to publish.
+    meters    : Meters,
+    levels    : Option<Levels>,
+    feedback  : Vec<(u8, u8)>,
+    // The states and the position last published on the feedback output.
+    // The changes of the loop states are also sent to the control side.
+    published_states   : Vec<Option<LoopState>>,
+    published_state    : Option<LooperState>,
+    published_position : Option<u8>,
+    state_changes      : Vec<(usize, LoopState)>,
+    midi      : MidiBuffers,
+    pool      : ChunkPool
 }
 
 impl Looper {
-    fn new(n_loops : usize, max_loop_length : usize, threshold : f32) -> Self {
-        Self {
+    // Create a looper with the given configuration, and the memory pool of its loops.
+    fn new(config : &Config, sample_rate : usize, buffer_size : usize, tempo : u32, pool : ChunkPool) -> Self {
+        let n_loops  = LOOP_COUNT;
+        let channels = config.channels;
+        let timing   = config.timing(sample_rate);
+        // Loop buffers start at the position of the first note in the first period.
+        let max_loop_length = seconds_to_samples(config.max_length, sample_rate) + buffer_size;
+        let limiter = Limiter::new(channels, sample_rate, config.limiter, db_to_amplitude(config.limiter_ceiling), config.auto_gain);
+        let n_delays = if config.multi_output { (n_loops + 1) * channels } else { 0 };
+        let mut looper = Self {
             state     : LooperState::Idle,
-            loops     : vec![Loop::new(max_loop_length) ; n_loops],
+            // Cloned vectors would lose the capacity reserved for the undo layers.
+            loops     : (0 .. n_loops).map(|_| Loop::new(channels, max_loop_length, config.undo_depth, timing)).collect(),
             cursor    : 0,
             from      : 0,
             to        : 0,
-            threshold : threshold
+            turns     : 0,
+            threshold : db_to_power(config.threshold),
+            pre_roll  : PreRoll::new(channels, ms_to_samples(config.pre_roll, sample_rate)),
+            pre_roll_pending : false,
+            trigger_on_note  : config.trigger_on_note,
+            note_on   : None,
+            latency   : timing.latency,
+            tempo     : tempo,
+            tempo_pending : None,
+            tempo_follow  : config.tempo_follow,
+            reference_length : 0,
+            reference_tempo  : tempo,
+            next_bar     : None,
+            state_at_bar : None,
+            scene_pending : None,
+            clock        : if config.midi_clock == Some(ClockMode::Send) { Some(MidiClock::new()) } else { None },
+            clock_follow : config.midi_clock == Some(ClockMode::Follow),
+            sample_rate  : sample_rate,
+            monitoring   : config.monitoring,
+            monitor_gain : Ramp::new(monitor_gain(config.monitoring, false, LooperState::Idle)),
+            effects   : EffectChain::new(channels, sample_rate),
+            buffers   : PeriodBuffers::new(config, n_loops, buffer_size),
+            output_delay : LatencyCompensation::new(n_delays, limiter.latency()),
+            limiter   : limiter,
+            clips     : 0,
+            meters    : Meters::new(n_loops, sample_rate),
+            levels    : None,
+            feedback  : Vec::with_capacity(MAX_FEEDBACK_EVENTS),
+            published_states   : vec![None ; n_loops],
+            published_state    : None,
+            published_position : None,
+            state_changes      : Vec::with_capacity(n_loops),
+            midi      : MidiBuffers::new(),
+            pool      : pool
+        };
+        for &i in &config.midi_loops {
+            looper.loops[i].midi = Some(MidiTrack::new(midi_loop::loop_channel(i)));
+        }
+        looper
+    }
+
+    fn send_loop_mode(&mut self, i : usize) {
+        let PlaybackMode { reverse, speed } = self.loops[i].mode;
+        self.send_feedback(LOOP_MODE_CC, midi314::loop_mode_value(i, reverse, speed));
+    }
+
+    fn send_feedback(&mut self, cc : u8, n : u8) {
+        // Drop the event instead of allocating memory in the process callback.
+        if self.feedback.len() < self.feedback.capacity() {
+            self.feedback.push((cc, n));
+        }
+    }
+
+    // Publish the changes of the loop states, of the looper state and of the position in the master loop,
+    // so that the other components follow the actual state of the looper.
+    fn publish_state(&mut self) {
+        for i in 0 .. self.loops.len() {
+            let state = self.loops[i].state;
+            if self.published_states[i] != Some(state) {
+                self.published_states[i] = Some(state);
+                self.send_feedback(LOOP_STATE_CC + i as u8, midi314::loop_state_value(state));
+                if self.state_changes.len() < self.state_changes.capacity() {
+                    self.state_changes.push((i, state));
+                }
+            }
+        }
+
+        if self.published_state != Some(self.state) {
+            self.published_state = Some(self.state);
+            let state = self.state as u8;
+            self.send_feedback(LOOPER_STATE_CC, state);
+        }
+
+        let (length, position) = self.master_position();
+        let position = if length > 0 { cmp::min(position as u64 * 128 / length as u64, 127) as u8 } else { 0 };
+        if self.published_position != Some(position) {
+            self.published_position = Some(position);
+            self.send_feedback(LOOP_POSITION_CC, position);
+        }
+    }
+
+    // Register a note-on event at the given time in the current period.
+    fn note_on(&mut self, time : usize) {
+        if self.note_on.is_none() {
+            self.note_on = Some(time);
+        }
+    }
+
+    // Register a MIDI event at the given time in the current period, for the MIDI loops.
+    fn midi_event(&mut self, time : usize, event : MidiEvent) {
+        self.midi.receive(time, event);
+    }
+
+    // Set the number of samples until the next bar of the Jack transport, when following it.
+    fn set_next_bar(&mut self, next_bar : Option<usize>) {
+        self.next_bar = next_bar;
+    }
+
+    // The number of samples until the next beat in the first loop, when following MIDI clock.
+    // The first loop contains at least one beat.
+    fn next_beat(&self) -> Option<usize> {
+        if !self.clock_follow {
+            return None
+        }
+        let beat     = 60.0 * self.sample_rate as f64 / self.tempo as f64;
+        let position = self.cursor as f64 - self.from as f64;
+        Some(((position / beat).ceil().max(1.0) * beat - position).round() as usize)
+    }
+
+    // The length of the master loop and the current position in it, when it has been recorded.
+    fn master_position(&self) -> (usize, usize) {
+        if self.state == LooperState::Running {
+            (self.to - self.from, self.cursor - self.from)
+        }
+        else {
+            (0, 0)
         }
     }
 
@@ -168,106 +1074,757 @@ impl Looper {
     }
 
     pub fn is_empty(&self) -> bool {
-        self.loops.iter().all(|ref s| s.state == LoopState::Empty)
+        // Deleted loops are not empty until they have faded out.
+        self.loops.iter().all(|ref s| s.state == LoopState::Empty && s.length == 0)
     }
 
-    fn update_state(&mut self, in_1 : &[f32], in_2 : &[f32]) {
+    fn update_state(&mut self, inputs : &[&[f32]]) {
         match self.state {
             LooperState::Idle =>
                 if self.is_recording() {
                     self.state = LooperState::WaitingFirstNote;
                 },
-            LooperState::WaitingFirstNote => {
-                let from = in_1.iter().zip(in_2.iter()).position(|(&x, &y)| x * x + y * y > self.threshold);
+            LooperState::WaitingFirstNote => if !self.is_recording() {
+                // The first loop has been deleted before its first note.
+                self.state = LooperState::Idle;
+            }
+            else {
+                let from = if let Some(bar) = self.next_bar {
+                    if bar < inputs[0].len() { Some(bar) } else { None }
+                }
+                else if self.trigger_on_note {
+                    self.note_on
+                }
+                else {
+                    (0 .. inputs[0].len()).position(|k| inputs.iter().map(|input| input[k] * input[k]).sum::<f32>() > self.threshold)
+                };
                 if let Some(f) = from  {
+                    // Start recording the first loop at the first note.
+                    for l in &mut self.loops {
+                        if l.state == LoopState::Recording {
+                            l.transition_time = f;
+                        }
+                    }
                     self.state = LooperState::RecordingFirstLoop;
                     self.from = f;
                     self.to = 0;
-                    self.cursor = 0;
+                    // The master loop starts with the pre-roll, before the first note.
+                    // The first note was played earlier, by the latency of the input.
+                    self.pre_roll.capture(inputs, f);
+                    self.pre_roll_pending = self.pre_roll.len() > 0;
+                    self.cursor = self.pre_roll.len() + self.latency;
                 }
             },
-            LooperState::RecordingFirstLoop =>
+            LooperState::RecordingFirstLoop => {
+                // Stop recording the first loop at the requested bar or beat,
+                // or immediately if the transport has stopped.
+                let bar = self.next_bar.or_else(|| self.next_beat()).unwrap_or(0);
+                if bar < inputs[0].len() {
+                    if let Some((i, state)) = self.state_at_bar.take() {
+                        self.loops[i].set_state(bar, state);
+                    }
+                }
+
+                // The first loop stops when it fills its buffer, and plays.
+                let end = self.loops[0].buffer.len().saturating_sub(self.cursor);
+                if end < inputs[0].len() {
+                    self.state_at_bar = None;
+                    for l in &mut self.loops {
+                        if l.state == LoopState::Recording {
+                            l.set_state(end, LoopState::Playing);
+                        }
+                        else if l.state_prev == LoopState::Recording {
+                            l.transition_time = cmp::min(l.transition_time, end);
+                        }
+                    }
+                }
+
                 if let Some(time) = self.recording_end_time() {
                     self.state = LooperState::Running;
+                    self.turns = 0;
                     self.to = self.cursor + time;
-                },
+                    self.reference_length = self.to - self.from;
+                    self.reference_tempo  = self.tempo;
+                }
+                else if !self.is_recording() {
+                    // The first loop has been deleted while recording.
+                    for l in &mut self.loops {
+                        if l.state_prev == LoopState::Recording {
+                            l.clear(&mut self.pool);
+                            l.state_prev = l.state;
+                        }
+                    }
+                    self.state = LooperState::Idle;
+                }
+            },
             LooperState::Running =>
                 if self.is_empty() {
                     self.state = LooperState::Idle;
                 }
         }
+        self.note_on = None;
     }
 
-    fn run(&mut self, in_1 : &[f32], in_2 : &[f32], out_1 : &mut [f32], out_2 : &mut [f32]) {
-        out_1.copy_from_slice(in_1);
-        out_2.copy_from_slice(in_2);
+    fn update_tempo(&mut self) {
+        let tempo = match self.tempo_pending {
+            Some(t) => t,
+            None    => return
+        };
 
-        if self.state >= LooperState::RecordingFirstLoop {
+        // Loops are not stretched while the input is written into them.
+        if self.state == LooperState::Running && self.loops.iter().any(|l| l.is_recording()) {
+            return
+        }
+        self.tempo_pending = None;
+        self.tempo = tempo;
+
+        if self.state == LooperState::Running && self.tempo_follow {
+            // The master loop keeps its length in beats, within the capacity of the loop buffers.
+            let max_length = self.loops[0].buffer.len() - self.from;
+            let length = (self.reference_length as u64 * self.reference_tempo as u64 / tempo as u64) as usize;
+            let length = cmp::min(cmp::max(1, length), max_length);
+
+            // Keep the current position in the master loop.
+            let position = ((self.cursor - self.from) as u64 * length as u64 / (self.to - self.from) as u64) as usize;
+            self.to     = self.from + length;
+            self.cursor = self.from + position;
             for l in &mut self.loops {
-                l.run(self.from, self.to, self.cursor, in_1, in_2, out_1, out_2);
+                l.set_master_length(length, position);
+            }
+        }
+    }
+
+    // Apply the pending scene at the beginning of the master loop in the current period,
+    // or immediately if the master loop is not running.
+    fn recall_scene(&mut self, len : usize) {
+        let scene = match self.scene_pending {
+            Some(s) => s,
+            None    => return
+        };
+        // The cursor is at the beginning of the master loop when it starts with the period.
+        let time = if self.state == LooperState::Running { (self.to - self.cursor) % (self.to - self.from) } else { 0 };
+        if time >= len {
+            return
+        }
+        self.scene_pending = None;
+
+        for i in 0 .. self.loops.len() {
+            // Loops that are empty, recording, or changing state in this period are not affected.
+            let state = self.loops[i].state;
+            let stable = state == self.loops[i].state_prev && (state == LoopState::Playing || state == LoopState::Muted);
+            if !stable {
+                continue
+            }
+            if let Some(s) = scene.states[i] {
+                if s != state {
+                    self.loops[i].set_state(time, s);
+                }
+                let gain = scene.gains[i];
+                if gain != self.loops[i].gain {
+                    self.loops[i].set_gain(time, gain);
+                    self.send_feedback(LOOP_GAIN_CC + i as u8, gain);
+                }
+            }
+        }
+    }
+
+    fn monitor(&mut self, inputs : &[&[f32]], outputs : &mut [&mut [f32]]) {
+        let gain = monitor_gain(self.monitoring, self.is_recording(), self.state);
+        if self.monitor_gain.target() != gain {
+            self.monitor_gain.set(gain, mix_ramp_length(self.sample_rate));
+        }
+
+        if self.monitor_gain.value() == self.monitor_gain.target() {
+            for (output, input) in outputs.iter_mut().zip(inputs.iter()) {
+                if gain > 0.0 {
+                    output.copy_from_slice(input);
+                }
+                else {
+                    for x in output.iter_mut() {
+                        *x = 0.0;
+                    }
+                }
             }
-            self.cursor += in_1.len();
+            return
+        }
+        for k in 0 .. inputs[0].len() {
+            let g = self.monitor_gain.next();
+            for (output, input) in outputs.iter_mut().zip(inputs.iter()) {
+                output[k] = g * input[k];
+            }
+        }
+    }
+
+    fn run(&mut self, inputs : &[&[f32]], outputs : &mut [&mut [f32]]) {
+        // The MIDI clock starts with the master loop, before the cursor moves.
+        let master = if self.state == LooperState::Running { Some(self.master_position()) } else { None };
+        if let Some(ref mut clock) = self.clock {
+            clock.run(inputs[0].len(), self.tempo, self.sample_rate, master);
+        }
+
+        self.monitor(inputs, outputs);
+        self.meters.input.update(inputs);
+
+        let len = inputs[0].len();
+        for buffers in &mut self.buffers.loop_outputs {
+            for b in buffers {
+                for x in b.iter_mut().take(len) {
+                    *x = 0.0;
+                }
+            }
+        }
+
+        if self.state >= LooperState::RecordingFirstLoop {
+            let master = MasterLoop { from : self.from, to : self.to };
+            // Until the control side has replaced the buffers after a change of the buffer size,
+            // loops are played without their effects and their meters.
+            let buffered = len <= self.buffers.len();
+            for (i, (l, meter)) in self.loops.iter_mut().zip(self.meters.loops.iter_mut()).enumerate() {
+                let starting = l.state == LoopState::Recording && l.state_prev != LoopState::Recording;
+                if l.midi.is_none() && buffered {
+                    // Play the loop into the scratch buffers, apply its effects and measure its level,
+                    // then mix it into the outputs.
+                    let mut buffers : [&mut [f32] ; MAX_CHANNELS] = Default::default();
+                    for (b, s) in buffers.iter_mut().zip(self.buffers.scratch.iter_mut()) {
+                        *b = &mut s[..len];
+                        for x in b.iter_mut() {
+                            *x = 0.0;
+                        }
+                    }
+                    let buffers = &mut buffers[..outputs.len()];
+                    l.run(master, self.cursor, inputs, buffers, &mut self.midi, &mut self.pool);
+                    l.effects.process(buffers);
+                    meter.update(buffers);
+                    if let Some(loop_output) = self.buffers.loop_outputs.get_mut(i) {
+                        for (o, b) in loop_output.iter_mut().zip(buffers.iter()) {
+                            o[..len].copy_from_slice(b);
+                        }
+                    }
+                    for (output, buffer) in outputs.iter_mut().zip(buffers.iter()) {
+                        for (y, x) in output.iter_mut().zip(buffer.iter()) {
+                            *y += *x;
+                        }
+                    }
+                }
+                else {
+                    l.run(master, self.cursor, inputs, outputs, &mut self.midi, &mut self.pool);
+                }
+                if starting && self.pre_roll_pending && l.midi.is_none() {
+                    l.prepend(self.from, self.pre_roll.samples(), &mut self.pool);
+                }
+            }
+            self.pre_roll_pending = false;
+            self.cursor += len;
             if self.state == LooperState::Running && self.cursor >= self.to {
                 self.cursor = self.from + (self.cursor - self.to);
+                self.turns += 1;
+            }
+        }
+        else {
+            self.pre_roll.push(inputs);
+        }
+
+        self.effects.process(outputs);
+
+        // Report the clips to the display, up to 127.
+        let playing = self.loops.iter().filter(|l| l.state == LoopState::Playing && l.midi.is_none()).count();
+        if self.limiter.process(outputs, playing) {
+            self.clips += 1;
+            if self.clips <= 127 {
+                let clips = self.clips as u8;
+                self.send_feedback(CLIP_COUNT_CC, clips);
+            }
+        }
+
+        // Until the buffers are replaced after a change of the buffer size, the separate outputs are silent.
+        if len <= self.buffers.len() {
+            for (output, input) in self.buffers.dry_output.iter_mut().zip(inputs.iter()) {
+                output[..len].copy_from_slice(input);
+            }
+            let buffers = self.buffers.loop_outputs.iter_mut().flat_map(|o| o.iter_mut()).chain(self.buffers.dry_output.iter_mut());
+            for (i, b) in buffers.enumerate() {
+                self.output_delay.process(i, &mut b[..len]);
             }
+            self.output_delay.advance(len);
+        }
+
+        self.meters.master.update(outputs);
+        if let Some(levels) = self.meters.publish(inputs[0].len()) {
+            self.levels = Some(levels);
         }
     }
 }
 
-impl LoopManager for Looper {
-    fn get_loop_count(&self) -> usize {
-        self.loops.len()
-    }
+impl Looper {
+    // Process one period, after applying the commands that are due in this period.
+    fn process(&mut self, commands : &mut CommandReceiver, frame_time : u32, inputs : &[&[f32]], outputs : &mut [&mut [f32]]) {
+        // The memory of the effects arrives before the commands that enable them.
+        while let Some((target, memory)) = commands.next_effect_memory() {
+            match self.loops.get_mut(target) {
+                Some(l) => l.effects.set_memory(memory),
+                None    => self.effects.set_memory(memory)
+            }
+        }
+        while let Some((time, command)) = commands.next(frame_time, inputs[0].len() as u32) {
+            self.apply(time, command);
+        }
 
-    fn set_loop_state(&mut self, loop_index : usize, time : usize, state : LoopState) {
-        self.loops[loop_index].set_state(time, state);
+        // Update the looper state.
+        self.update_state(inputs);
+        self.update_tempo();
+        self.recall_scene(inputs[0].len());
+
+        // Process the audio data.
+        self.run(inputs, outputs);
+        self.midi.input.clear();
+
+        self.publish_state();
     }
 
-    fn get_loop_state(&self, loop_index : usize) -> LoopState {
-        self.loops[loop_index].state
+    fn apply(&mut self, time : usize, command : Command) {
+        match command {
+            Command::SetState(i, state)     => {
+                // When following the transport, the end of the first loop is delayed until the next bar,
+                // and when following MIDI clock, until the next beat.
+                let recording = self.loops[i].state == LoopState::Recording;
+                let stopping  = state == LoopState::Playing || state == LoopState::Muted;
+                if stopping && self.loops[i].state == LoopState::Empty {
+                    // An empty loop cannot play: publish its state again for the control side.
+                    self.published_states[i] = None;
+                }
+                else if (self.next_bar.is_some() || self.clock_follow) && self.state == LooperState::RecordingFirstLoop && recording && stopping {
+                    self.state_at_bar = Some((i, state));
+                }
+                else {
+                    self.loops[i].set_state(time, state);
+                }
+            },
+            Command::Undo(i)                => self.loops[i].undo(time),
+            Command::Redo(i)                => self.loops[i].redo(time),
+            Command::SetGain(i, gain)       => {
+                self.loops[i].set_gain(time, gain);
+                self.send_feedback(LOOP_GAIN_CC + i as u8, gain);
+            },
+            Command::SetPan(i, pan)         => {
+                self.loops[i].set_pan(time, pan);
+                self.send_feedback(LOOP_PAN_CC + i as u8, pan);
+            },
+            Command::SetReverse(i, reverse) => {
+                self.loops[i].set_reverse(reverse);
+                self.send_loop_mode(i);
+            },
+            Command::SetSpeed(i, speed)     => {
+                self.loops[i].set_speed(speed);
+                self.send_loop_mode(i);
+            },
+            Command::SetProgram(i, program) => self.loops[i].set_program(program),
+            Command::SetEffectParam(i, effect, param, value) => match self.loops.get_mut(i) {
+                Some(l) => l.effects.set_param(effect, param, value),
+                None    => self.effects.set_param(effect, param, value)
+            },
+            Command::SetTempo(tempo)        => self.tempo_pending = Some(tempo),
+            Command::SetMonitoring(mode)    => self.monitoring = mode,
+            Command::RecallScene(scene)     => self.scene_pending = Some(scene),
+            Command::SetTiming(timing)      => {
+                self.latency     = timing.latency;
+                self.sample_rate = timing.sample_rate;
+                self.effects.set_sample_rate(timing.sample_rate);
+                self.limiter.set_sample_rate(timing.sample_rate);
+                self.meters.set_sample_rate(timing.sample_rate);
+                for l in &mut self.loops {
+                    l.set_timing(timing);
+                }
+            }
+        }
     }
 }
 
+// Create a looper with the given configuration, and the control side of its memory pool.
+// The duration of gain and pan changes, in samples.
+fn mix_ramp_length(sample_rate : usize) -> usize {
+    seconds_to_samples(MIX_RAMP_DURATION, sample_rate)
+}
+
+// The gain of the input in the outputs, depending on the monitoring mode and the state of the looper.
+fn monitor_gain(mode : MonitorMode, recording : bool, state : LooperState) -> f32 {
+    let monitor = match mode {
+        MonitorMode::Always    => true,
+        MonitorMode::Never     => false,
+        MonitorMode::Recording => recording,
+        MonitorMode::Idle      => state <= LooperState::WaitingFirstNote
+    };
+    if monitor { 1.0 } else { 0.0 }
+}
+
+fn new_looper(config : &Config, sample_rate : usize, buffer_size : usize, tempo : u32) -> (Looper, ChunkAllocator) {
+    // Loop memory is allocated from a pool shared by all loops, as they are recorded.
+    let (mut allocator, pool) = pool::pool(config.channels, seconds_to_samples(config.memory, sample_rate));
+    allocator.refill();
+    (Looper::new(config, sample_rate, buffer_size, tempo, pool), allocator)
+}
+
 fn main() {
-    // Create a default state and show it.
     // TODO Take args from config file.
-    let mut looper = Looper::new(9, 48000 * 60, 1.0e-4);
+    let config = Config::from_args();
+    if config.check_alloc {
+        alloc_check::enable();
+    }
+
+    if config.render.is_some() {
+        offline::render(&config);
+        return
+    }
+
+    if let Some(ref port) = config.calibrate {
+        match jack::Client::new("midi314-looper", jack::ClientOptions::NO_START_SERVER) {
+            Ok((client, _status)) => calibrate::calibrate(&config, client, port),
+            Err(e) => {
+                eprintln!("Could not connect to the Jack server: {:?}", e);
+                process::exit(1)
+            }
+        }
+        return
+    }
+
+    loop {
+        match jack::Client::new("midi314-looper", jack::ClientOptions::NO_START_SERVER) {
+            Ok((client, _status)) => run(&config, client),
+            Err(e) => if !config.reconnect {
+                eprintln!("Could not connect to the Jack server: {:?}", e);
+                process::exit(1)
+            }
+        }
+
+        if !config.reconnect {
+            process::exit(1)
+        }
+        thread::sleep(time::Duration::from_secs(1))
+    }
+}
+
+fn timing(config : &Config, client : &jack::Client, sample_rate : usize, audio_in_name : &str) -> Timing {
+    let mut timing = config.timing(sample_rate);
+
+    // Unless it is configured, the latency of the input is the capture latency reported by Jack.
+    if config.latency.is_none() {
+        if let Some(port) = client.port_by_name(audio_in_name) {
+            let (_, max) = port.get_latency_range(jack::LatencyType::Capture);
+            timing.latency = max as usize;
+        }
+    }
+    timing
+}
+
+// Run the looper until the Jack server shuts down.
+fn run(config : &Config, client : jack::Client) {
+    let status = ServerStatus::new(&client);
+
+    // Create a default state.
+    let mut sample_rate = status.sample_rate();
+    let mut buffer_size = status.buffer_size();
     let mut keyboard = Keyboard::new();
+    let (mut looper, mut allocator) = new_looper(config, sample_rate, buffer_size, keyboard.tempo);
+
+    // Create the queues between the control side and the audio engine.
+    // Period buffers are allocated and deallocated on the control side when the buffer size changes,
+    // and so is the whole audio engine when the sample rate changes.
+    let (mut midi_producer, mut midi_consumer)       = RingBuffer::<MidiEvent>::new(QUEUE_CAPACITY).split();
+    let (mut state_producer, mut state_consumer)     = RingBuffer::<(usize, LoopState)>::new(QUEUE_CAPACITY).split();
+    let (mut level_producer, mut level_consumer)     = RingBuffer::<Levels>::new(QUEUE_CAPACITY).split();
+    let (mut buffer_producer, mut buffer_consumer)   = RingBuffer::<PeriodBuffers>::new(1).split();
+    let (mut release_producer, mut release_consumer) = RingBuffer::<PeriodBuffers>::new(1).split();
+    let (mut engine_producer, mut engine_consumer)   = RingBuffer::<(Looper, CommandReceiver)>::new(1).split();
+    let (mut retired_producer, mut retired_consumer) = RingBuffer::<(Looper, CommandReceiver)>::new(1).split();
+    let (mut controller, mut commands) = control::channel(looper.loops.len(), config.channels, sample_rate);
+    let looper_count   = looper.loops.len();
+
+    // Restore the settings of the previous session.
+    // Like all commands, they are applied one period later.
+    if let Some(ref file_name) = config.session {
+        if Path::new(file_name).exists() {
+            let time = client.frame_time().wrapping_add(buffer_size as u32) as usize;
+            match Session::load(file_name, &Session::capture(&keyboard, &controller)) {
+                Ok(s)  => s.restore(&mut keyboard, &mut controller, time),
+                Err(e) => eprintln!("Could not read {}: {}", file_name, e)
+            }
+        }
+    }
+
+    let mut osc_server = config.osc_port.map(|port| OscServer::new(config.osc_address, port, config.session.clone()).unwrap_or_else(|e| {
+        eprintln!("Could not start the OSC server on {}:{}: {}", config.osc_address, port, e);
+        process::exit(1)
+    }));
+
+    // Register MIDI and audio I/O ports.
+    let     midi_in   = client.register_port("midi_in",  jack::MidiIn::default()).unwrap();
+    let mut midi_out  = client.register_port("midi_out", jack::MidiOut::default()).unwrap();
+    let mut loops_out = client.register_port("midi_loops_out", jack::MidiOut::default()).unwrap();
+    let mut clock_out = looper.clock.as_ref().map(|_| client.register_port("clock_out", jack::MidiOut::default()).unwrap());
+    let     audio_in  : Vec<_> = (1 ..= config.channels).map(|c| client.register_port(&format!("audio_in_{}",  c), jack::AudioIn::default()).unwrap()).collect();
+    let mut audio_out : Vec<_> = (1 ..= config.channels).map(|c| client.register_port(&format!("audio_out_{}", c), jack::AudioOut::default()).unwrap()).collect();
+
+    // In multi-output mode, each loop and the dry input have their own ports,
+    // and the audio_out ports carry the master output.
+    let mut loop_out : Vec<Vec<_>> = (1 ..= looper.buffers.loop_outputs.len()).map(|i| (1 ..= config.channels).map(|c|
+        client.register_port(&format!("loop_{}_out_{}", i, c), jack::AudioOut::default()).unwrap()).collect()).collect();
+    let mut dry_out : Vec<_> = if config.multi_output {
+        (1 ..= config.channels).map(|c| client.register_port(&format!("dry_out_{}", c), jack::AudioOut::default()).unwrap()).collect()
+    }
+    else {
+        Vec::new()
+    };
+
+    let audio_in_name = audio_in[0].name().to_string();
+    let engine_status = status.clone();
+    let mut xruns = 0;
 
-    // Open Jack client and register a MIDI input port and audio I/O ports.
-    let (client, _status) = jack::Client::new("midi314-looper", jack::ClientOptions::NO_START_SERVER).unwrap();
+    // As timebase master, the looper publishes its tempo and the position of the master loop.
+    let transport_mode  = config.transport;
+    let timebase        = Arc::new(Timebase::new(keyboard.tempo, config.beats_per_bar));
+    let engine_timebase = timebase.clone();
 
-    let     midi_in     = client.register_port("midi_in",     jack::MidiIn::default()).unwrap();
-    let     audio_in_1  = client.register_port("audio_in_1",  jack::AudioIn::default()).unwrap();
-    let     audio_in_2  = client.register_port("audio_in_2",  jack::AudioIn::default()).unwrap();
-    let mut audio_out_1 = client.register_port("audio_out_1", jack::AudioOut::default()).unwrap();
-    let mut audio_out_2 = client.register_port("audio_out_2", jack::AudioOut::default()).unwrap();
+    let cback = move |client : &jack::Client, ps : &jack::ProcessScope| -> jack::Control {
+        alloc_check::enter_callback();
+
+        // Replace the audio engine after a change of the sample rate,
+        // and give the old one back to the control side.
+        if !retired_producer.is_full() {
+            if let Some((l, c)) = engine_consumer.pop() {
+                let _ = retired_producer.push((mem::replace(&mut looper, l), mem::replace(&mut commands, c)));
+            }
+        }
+
+        // When following the transport, the first loop starts and stops on a bar.
+        let position = transport_mode.map(|_| transport::query(client));
+        if transport_mode == Some(TransportMode::Follower) {
+            looper.set_next_bar(position.and_then(|p| if p.rolling { p.next_bar } else { None }));
+        }
 
-    let cback = move |_ : &jack::Client, ps : &jack::ProcessScope| -> jack::Control {
-        // Process MIDI events and update the current state.
+        // Send MIDI events to the control side.
+        let frame_time = ps.last_frame_time();
         for e in midi_in.iter(ps) {
-            keyboard.update(&mut looper, e.time as usize, e.bytes.to_vec());
+            if let Some(evt) = MidiEvent::new(frame_time.wrapping_add(e.time), e.bytes) {
+                if evt.is_note_on() {
+                    looper.note_on(e.time as usize);
+                }
+                looper.midi_event(e.time as usize, evt);
+                let _ = midi_producer.push(evt);
+            }
         }
 
         // Get the current audio buffers.
-        let     in_1  = audio_in_1.as_slice(ps);
-        let     in_2  = audio_in_2.as_slice(ps);
-        let mut out_1 = audio_out_1.as_mut_slice(ps);
-        let mut out_2 = audio_out_2.as_mut_slice(ps);
+        let mut inputs  : [&[f32] ; MAX_CHANNELS]     = Default::default();
+        let mut outputs : [&mut [f32] ; MAX_CHANNELS] = Default::default();
+        for (i, p) in inputs.iter_mut().zip(audio_in.iter()) {
+            *i = p.as_slice(ps);
+        }
+        for (o, p) in outputs.iter_mut().zip(audio_out.iter_mut()) {
+            *o = p.as_mut_slice(ps);
+        }
+        let inputs  = &inputs[..audio_in.len()];
+        let outputs = &mut outputs[..audio_in.len()];
 
-        // Update the looper state.
-        looper.update_state(&in_1, &in_2);
+        // Replace the period buffers after a change of the buffer size,
+        // and give the old ones back to the control side.
+        if !release_producer.is_full() {
+            if let Some(buffers) = buffer_consumer.pop() {
+                let _ = release_producer.push(mem::replace(&mut looper.buffers, buffers));
+            }
+        }
 
-        // Process the audio data.
-        looper.run(&in_1, &in_2, &mut out_1, &mut out_2);
+        // Process the audio data with the commands from the control side.
+        looper.process(&mut commands, frame_time, inputs, outputs);
+
+        // Send each loop and the dry input to their own ports.
+        let ports   = loop_out.iter_mut().flat_map(|p| p.iter_mut()).chain(dry_out.iter_mut());
+        let buffers = looper.buffers.loop_outputs.iter().flat_map(|b| b.iter()).chain(looper.buffers.dry_output.iter());
+        for (p, b) in ports.zip(buffers) {
+            let out = p.as_mut_slice(ps);
+            let n = cmp::min(out.len(), b.len());
+            out[..n].copy_from_slice(&b[..n]);
+            for x in &mut out[n..] {
+                *x = 0.0;
+            }
+        }
 
+        // Publish the position in the master loop for the next period.
+        if let (Some(TransportMode::Master), Some(p)) = (transport_mode, position) {
+            let (length, cursor) = looper.master_position();
+            let frame = if p.rolling { p.frame.wrapping_add(ps.n_frames()) } else { p.frame };
+            engine_timebase.update(looper.tempo, length, frame.wrapping_sub(cursor as u32), looper.turns);
+        }
+
+        // Report the xruns to the display.
+        let n = engine_status.xruns();
+        if n != xruns {
+            xruns = n;
+            looper.send_feedback(XRUN_COUNT_CC, cmp::min(n, 127) as u8);
+        }
+
+        // Report the changes to the loop parameters.
+        let mut writer = midi_out.writer(ps);
+        for (cc, n) in looper.feedback.drain(..) {
+            let _ = writer.write(&jack::RawMidi { time : 0, bytes : &midi314::control_change(cc, n) });
+        }
+
+        // Send the actual loop states to the control side.
+        for s in looper.state_changes.drain(..) {
+            let _ = state_producer.push(s);
+        }
+
+        // Send the levels to the control side.
+        if let Some(levels) = looper.levels.take() {
+            let _ = level_producer.push(levels);
+        }
+
+        // Play the MIDI loops.
+        let mut writer = loops_out.writer(ps);
+        for e in looper.midi.output.drain(..) {
+            let _ = writer.write(&jack::RawMidi { time : e.time as u32, bytes : &e.event.bytes[.. e.event.len] });
+        }
+
+        // Send the MIDI clock.
+        if let (Some(port), Some(clock)) = (clock_out.as_mut(), looper.clock.as_mut()) {
+            let mut writer = port.writer(ps);
+            for e in clock.output.drain(..) {
+                let _ = writer.write(&jack::RawMidi { time : e.time as u32, bytes : &e.event.bytes[.. e.event.len] });
+            }
+        }
+
+        alloc_check::leave_callback();
         jack::Control::Continue
     };
 
-    let _active_client = client.activate_async((), jack::ClosureProcessHandler::new(cback)).unwrap();
+    let active_client = client.activate_async(Notifications::new(status.clone()), jack::ClosureProcessHandler::new(cback)).unwrap();
+    if transport_mode == Some(TransportMode::Master) && !transport::set_timebase(active_client.as_client(), &timebase) {
+        eprintln!("Could not become the Jack timebase master");
+    }
 
-    // Wait.
-    loop {
-        thread::sleep(time::Duration::from_secs(1))
+    // When following the transport, the loops that it stopped are restarted with it.
+    let mut rolling = transport::is_rolling(active_client.as_client());
+    let mut stopped_loops = vec![false ; looper_count];
+
+    // When following MIDI clock, the tempo is measured from the clock events received.
+    let mut clock_follower = ClockFollower::new();
+
+    // Process MIDI events on the control side.
+    let mut last_report = time::Instant::now();
+    while !status.is_shut_down() {
+        // Commands are applied one period after the MIDI events that caused them,
+        // so that they keep an accurate timing.
+        let delay = status.buffer_size() as u32;
+        if status.buffer_size() != buffer_size && !buffer_producer.is_full() {
+            buffer_size = status.buffer_size();
+            let _ = buffer_producer.push(PeriodBuffers::new(config, looper_count, buffer_size));
+        }
+        while let Some(buffers) = release_consumer.pop() {
+            drop(buffers);
+        }
+
+        // Loops keep their lengths in samples, so they are cleared when the sample rate changes.
+        // A new audio engine is created for the new rate, and the settings are sent to it again.
+        if status.sample_rate() != sample_rate && !engine_producer.is_full() {
+            sample_rate = status.sample_rate();
+            eprintln!("The sample rate has changed to {} Hz: the loops are cleared", sample_rate);
+            let (new_looper, new_allocator) = new_looper(config, sample_rate, buffer_size, keyboard.tempo);
+            allocator = new_allocator;
+            let _ = engine_producer.push((new_looper, controller.reconnect(sample_rate)));
+
+            let client = active_client.as_client();
+            let time = client.frame_time().wrapping_add(delay) as usize;
+            Session::capture(&keyboard, &controller).restore(&mut keyboard, &mut controller, time);
+            if let Some(mode) = controller.get_monitoring() {
+                controller.set_monitoring(time, mode);
+            }
+            controller.set_timing(time, timing(config, client, sample_rate, &audio_in_name));
+            stopped_loops.fill(false);
+        }
+        while let Some(engine) = retired_consumer.pop() {
+            drop(engine);
+        }
+
+        // The control side follows the loop states published by the audio engine.
+        while let Some((i, state)) = state_consumer.pop() {
+            controller.loop_state_changed(i, 0, state);
+        }
+
+        while let Some(e) = midi_consumer.pop() {
+            let time = e.time.wrapping_add(delay) as usize;
+            if config.midi_clock == Some(ClockMode::Follow) {
+                if let Some(tempo) = clock_follower.update(e.time, &e, sample_rate) {
+                    if tempo != keyboard.tempo {
+                        keyboard.tempo = tempo;
+                        controller.set_tempo(time, tempo);
+                    }
+                }
+            }
+            keyboard.update(&mut controller, time, e.to_vec());
+        }
+
+        if let Some(ref mut server) = osc_server {
+            let time = active_client.as_client().frame_time().wrapping_add(delay) as usize;
+            server.poll(&mut keyboard, &mut controller, time);
+        }
+
+        // Publish the levels of the input, the master output and the loops.
+        while let Some(levels) = level_consumer.pop() {
+            if config.meters {
+                meter::print_levels(&levels, looper_count);
+            }
+            if let Some(ref mut server) = osc_server {
+                server.notify_levels(&levels, looper_count);
+            }
+        }
+
+        if transport_mode == Some(TransportMode::Follower) {
+            let r = transport::is_rolling(active_client.as_client());
+            if r != rolling {
+                rolling = r;
+                let time = active_client.as_client().frame_time().wrapping_add(delay) as usize;
+                follow_transport(&mut controller, time, rolling, &mut stopped_loops);
+            }
+        }
+
+        // Latency compensation follows the changes in the connections.
+        if status.take_latency_changed() && config.latency.is_none() {
+            let client = active_client.as_client();
+            controller.set_timing(client.frame_time() as usize, timing(config, client, sample_rate, &audio_in_name));
+        }
+
+        allocator.refill();
+        if allocator.take_exhausted() {
+            eprintln!("Loop memory is full: a recording has been truncated");
+        }
+
+        if config.check_alloc && last_report.elapsed() >= time::Duration::from_secs(1) {
+            let n = alloc_check::take_count();
+            if n > 0 {
+                eprintln!("{} memory allocations in the process callback", n);
+            }
+            last_report = time::Instant::now();
+        }
+
+        thread::sleep(time::Duration::from_millis(1))
+    }
+
+    if transport_mode == Some(TransportMode::Master) {
+        transport::release_timebase(active_client.as_client());
+    }
+}
+
+// Mute the playing loops when the transport stops, and play them again when it starts.
+fn follow_transport(controller : &mut Controller, time : usize, rolling : bool, stopped_loops : &mut [bool]) {
+    for (i, stopped) in stopped_loops.iter_mut().enumerate() {
+        let state = controller.get_loop_state(i);
+        if !rolling && state == LoopState::Playing {
+            controller.set_loop_state(i, time, LoopState::Muted);
+            *stopped = true;
+        }
+        else if rolling && *stopped {
+            if state == LoopState::Muted {
+                controller.set_loop_state(i, time, LoopState::Playing);
+            }
+            *stopped = false;
+        }
     }
 }
@@ -0,0 +1,220 @@
+
+use std::io::{self, Write};
+use control::LOOP_COUNT;
+use config::seconds_to_samples;
+
+// The interval between two publications of the levels, in seconds.
+pub const METER_INTERVAL : f32 = 0.05;
+
+// The peak level falls by 20 dB in this duration, in seconds.
+const PEAK_FALL_TIME : f32 = 1.5;
+
+// The time constant of the RMS level, in seconds.
+const RMS_TIME : f32 = 0.3;
+
+// The range of the meters printed in the terminal, in dBFS, and their width in characters.
+const MIN_DB    : f32 = -48.0;
+const BAR_WIDTH : usize = 8;
+
+// The levels of a signal, as amplitudes.
+// Clip is true if a sample reached full scale since the last publication.
+#[derive(Clone, Copy)]
+pub struct Level {
+    pub peak : f32,
+    pub rms  : f32,
+    pub clip : bool
+}
+
+// The levels of the input, of the master output and of each loop, published by the audio engine.
+#[derive(Clone, Copy)]
+pub struct Levels {
+    pub input  : Level,
+    pub master : Level,
+    pub loops  : [Level ; LOOP_COUNT]
+}
+
+// A peak and RMS meter over all channels of a signal.
+// The peak level rises immediately and falls slowly, and the RMS level is averaged like a VU meter.
+#[derive(Clone)]
+pub struct Meter {
+    peak        : f32,
+    mean_square : f32,
+    clip        : bool,
+    peak_coef   : f32,
+    rms_coef    : f32
+}
+
+impl Meter {
+    pub fn new(sample_rate : usize) -> Self {
+        let mut meter = Self {
+            peak        : 0.0,
+            mean_square : 0.0,
+            clip        : false,
+            peak_coef   : 0.0,
+            rms_coef    : 0.0
+        };
+        meter.set_sample_rate(sample_rate);
+        meter
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate : usize) {
+        let sr = sample_rate as f32;
+        self.peak_coef = (0.1f32.ln() / (PEAK_FALL_TIME * sr)).exp();
+        self.rms_coef  = 1.0 - (-1.0 / (RMS_TIME * sr)).exp();
+    }
+
+    pub fn update<T : AsRef<[f32]>>(&mut self, buffers : &[T]) {
+        let len = buffers.first().map_or(0, |b| b.as_ref().len());
+        let n = buffers.len() as f32;
+        for k in 0 .. len {
+            let mut peak = 0.0f32;
+            let mut sum  = 0.0;
+            for b in buffers {
+                let x = b.as_ref()[k];
+                peak = peak.max(x.abs());
+                sum += x * x;
+            }
+            self.peak = peak.max(self.peak * self.peak_coef);
+            self.mean_square += (sum / n - self.mean_square) * self.rms_coef;
+            self.clip = self.clip || peak >= 1.0;
+        }
+    }
+
+    // Get the current levels, and start detecting clips again.
+    pub fn take_level(&mut self) -> Level {
+        let level = Level {
+            peak : self.peak,
+            rms  : self.mean_square.sqrt(),
+            clip : self.clip
+        };
+        self.clip = false;
+        level
+    }
+}
+
+// The meters of the looper, published at a low rate.
+pub struct Meters {
+    pub input  : Meter,
+    pub master : Meter,
+    pub loops  : Vec<Meter>,
+    countdown  : usize,
+    interval   : usize
+}
+
+impl Meters {
+    pub fn new(n_loops : usize, sample_rate : usize) -> Self {
+        let interval = seconds_to_samples(METER_INTERVAL, sample_rate);
+        Self {
+            input     : Meter::new(sample_rate),
+            master    : Meter::new(sample_rate),
+            loops     : vec![Meter::new(sample_rate) ; n_loops],
+            countdown : interval,
+            interval  : interval
+        }
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate : usize) {
+        self.input.set_sample_rate(sample_rate);
+        self.master.set_sample_rate(sample_rate);
+        for m in &mut self.loops {
+            m.set_sample_rate(sample_rate);
+        }
+        self.interval = seconds_to_samples(METER_INTERVAL, sample_rate);
+    }
+
+    // Count the samples of a period, and get the levels when they must be published.
+    pub fn publish(&mut self, len : usize) -> Option<Levels> {
+        if len < self.countdown {
+            self.countdown -= len;
+            return None
+        }
+        self.countdown = self.interval;
+        let silent = Level { peak : 0.0, rms : 0.0, clip : false };
+        let mut levels = Levels {
+            input  : self.input.take_level(),
+            master : self.master.take_level(),
+            loops  : [silent ; LOOP_COUNT]
+        };
+        for (l, m) in levels.loops.iter_mut().zip(self.loops.iter_mut()) {
+            *l = m.take_level();
+        }
+        Some(levels)
+    }
+}
+
+// Convert an amplitude into dBFS.
+pub fn amplitude_to_db(a : f32) -> f32 {
+    20.0 * a.max(1.0e-10).log10()
+}
+
+// Draw the peak levels as bars on a single line of the terminal.
+// A clip is shown with a ! after the bar.
+pub fn print_levels(levels : &Levels, n_loops : usize) {
+    let bar = |level : &Level| {
+        let db = amplitude_to_db(level.peak).max(MIN_DB);
+        let n  = ((db - MIN_DB) / -MIN_DB * BAR_WIDTH as f32).round() as usize;
+        format!("{:<width$}{}", "#".repeat(n), if level.clip { '!' } else { ' ' }, width = BAR_WIDTH)
+    };
+    let mut line = format!("\rIn [{}] Out [{}]", bar(&levels.input), bar(&levels.master));
+    for (i, l) in levels.loops.iter().enumerate().take(n_loops) {
+        line.push_str(&format!(" {} [{}]", i + 1, bar(l)));
+    }
+    print!("{}", line);
+    let _ = io::stdout().flush();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{amplitude_to_db, Meter, Meters, PEAK_FALL_TIME, RMS_TIME};
+
+    const SAMPLE_RATE : usize = 48000;
+
+    fn samples(duration : f32) -> usize {
+        (duration * SAMPLE_RATE as f32).round() as usize
+    }
+
+    #[test]
+    fn peak_ballistics() {
+        let mut meter = Meter::new(SAMPLE_RATE);
+        // The peak level rises immediately, over all channels.
+        meter.update(&[vec![0.0, 0.3, 0.1], vec![0.0, 0.2, -0.8]]);
+        assert_eq!(meter.take_level().peak, 0.8);
+
+        // It falls by 20 dB in the fall time.
+        meter.update(&[vec![0.0 ; samples(PEAK_FALL_TIME)]]);
+        let db = amplitude_to_db(meter.take_level().peak);
+        assert!((db - amplitude_to_db(0.08)).abs() < 0.1);
+    }
+
+    #[test]
+    fn rms_ballistics() {
+        let mut meter = Meter::new(SAMPLE_RATE);
+        // The RMS level reaches 1 - 1/e of the mean square after the time constant.
+        meter.update(&[vec![0.5 ; samples(RMS_TIME)], vec![-0.5 ; samples(RMS_TIME)]]);
+        let expected = (0.25 * (1.0 - (-1.0f32).exp())).sqrt();
+        assert!((meter.take_level().rms - expected).abs() < 1.0e-3);
+
+        meter.update(&[vec![0.5 ; samples(10.0 * RMS_TIME)], vec![-0.5 ; samples(10.0 * RMS_TIME)]]);
+        assert!((meter.take_level().rms - 0.5).abs() < 1.0e-3);
+    }
+
+    #[test]
+    fn clip() {
+        let mut meter = Meter::new(SAMPLE_RATE);
+        meter.update(&[vec![0.99, -0.5]]);
+        assert!(!meter.take_level().clip);
+        meter.update(&[vec![0.5, -1.0, 0.5]]);
+        meter.update(&[vec![0.0]]);
+        // A clip is reported once.
+        assert!(meter.take_level().clip);
+        assert!(!meter.take_level().clip);
+    }
+
+    #[test]
+    fn publication_interval() {
+        let mut meters = Meters::new(2, SAMPLE_RATE);
+        // The levels are published every 50 ms.
+        let published : Vec<usize> = (1 ..= 40).filter(|_| meters.publish(256).is_some()).collect();
+        assert_eq!(published, vec![10, 20, 30, 40]);
+    }
+}
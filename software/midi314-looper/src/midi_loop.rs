@@ -0,0 +1,251 @@
+
+use std::cmp;
+use midi314;
+use control::MidiEvent;
+
+// The maximum number of MIDI events received or sent in each period.
+const MAX_PERIOD_EVENTS : usize = 256;
+
+// The maximum number of events recorded in a MIDI loop.
+const MAX_LOOP_EVENTS : usize = 4096;
+
+// The number of MIDI notes.
+const NOTE_COUNT : usize = 128;
+
+// The MIDI channel of the percussion instruments (channel 10).
+const PERCUSSION_CHANNEL : u8 = 9;
+
+// A MIDI event at a time in the current period, or at a position in a loop.
+#[derive(Clone, Copy)]
+pub struct TimedEvent {
+    pub time  : usize,
+    pub event : MidiEvent
+}
+
+// The MIDI events of the current period, shared by all loops.
+pub struct MidiBuffers {
+    pub input    : Vec<TimedEvent>,
+    pub output   : Vec<TimedEvent>,
+    pub programs : [u8 ; 16]
+}
+
+impl MidiBuffers {
+    pub fn new() -> Self {
+        Self {
+            input    : Vec::with_capacity(MAX_PERIOD_EVENTS),
+            output   : Vec::with_capacity(MAX_PERIOD_EVENTS),
+            programs : [0 ; 16]
+        }
+    }
+
+    // Register an event received at the given time in the current period.
+    pub fn receive(&mut self, time : usize, event : MidiEvent) {
+        // Keep the current program of each channel, for the loops that will be recorded.
+        if event.len == 2 && event.bytes[0] & 0xF0 == 0xC0 {
+            self.programs[(event.bytes[0] & 0x0F) as usize] = event.bytes[1];
+        }
+        // Drop the event instead of allocating memory in the process callback.
+        if self.input.len() < self.input.capacity() {
+            self.input.push(TimedEvent { time : time, event : event });
+        }
+    }
+
+    // Add an event to the output, after the events that are sent at the same time or earlier.
+    pub fn send(&mut self, time : usize, bytes : &[u8]) {
+        if self.output.len() == self.output.capacity() {
+            return
+        }
+        if let Some(event) = MidiEvent::new(0, bytes) {
+            let index = self.output.iter().position(|e| e.time > time).unwrap_or(self.output.len());
+            self.output.insert(index, TimedEvent { time : time, event : event });
+        }
+    }
+}
+
+// The events of a MIDI loop.
+// Positions are counted in samples from the beginning of the loop, as for audio loops.
+// Events are sent on the channel of the loop, with the program that was selected when it was recorded.
+#[derive(Clone)]
+pub struct MidiTrack {
+    channel    : u8,
+    program    : u8,
+    percussion : bool,
+    events     : Vec<TimedEvent>,
+    // The notes that are on, while recording or while playing.
+    held       : [bool ; NOTE_COUNT],
+    playing    : bool
+}
+
+impl MidiTrack {
+    pub fn new(channel : u8) -> Self {
+        Self {
+            channel    : channel,
+            program    : 0,
+            percussion : false,
+            // Reserve room for the note-off events added at the end of a recording.
+            events     : Vec::with_capacity(MAX_LOOP_EVENTS + NOTE_COUNT),
+            held       : [false ; NOTE_COUNT],
+            playing    : false
+        }
+    }
+
+    fn output_channel(&self) -> u8 {
+        if self.percussion { PERCUSSION_CHANNEL } else { self.channel }
+    }
+
+    pub fn set_program(&mut self, program : u8) {
+        self.program = program;
+        // Send the new program when the loop plays again.
+        self.playing = false;
+    }
+
+    pub fn clear(&mut self) {
+        self.events.clear();
+        self.held = [false ; NOTE_COUNT];
+    }
+
+    // Record the events of the current period from start to end, the cursor being the position at start.
+    pub fn record(&mut self, midi : &MidiBuffers, start : usize, end : usize, cursor : usize) {
+        for e in midi.input.iter().filter(|e| e.time >= start && e.time < end) {
+            if !is_recordable(&e.event) {
+                continue
+            }
+
+            // The first event gives the channel and the program of the recording.
+            let channel = e.event.bytes[0] & 0x0F;
+            if self.events.is_empty() {
+                self.percussion = channel == PERCUSSION_CHANNEL;
+                self.program    = midi.programs[channel as usize];
+            }
+
+            if self.events.len() < MAX_LOOP_EVENTS {
+                if let Some(note) = note_on(&e.event) {
+                    self.held[note] = true;
+                }
+                else if let Some(note) = note_off(&e.event) {
+                    self.held[note] = false;
+                }
+                self.events.push(TimedEvent { time : cursor + e.time - start, event : e.event });
+            }
+        }
+    }
+
+    pub fn stop_recording(&mut self, end : usize, length : usize) {
+        // Drop the events that do not fit in the loop,
+        // and release the notes that are still held at the end of the recording.
+        let end = cmp::min(end, length);
+        while self.events.last().is_some_and(|e| e.time >= end) {
+            self.events.pop();
+        }
+        for note in 0 .. NOTE_COUNT {
+            if self.held[note] {
+                if let Some(event) = MidiEvent::new(0, &[0x80, note as u8, 0]) {
+                    self.events.push(TimedEvent { time : end.saturating_sub(1), event : event });
+                }
+                self.held[note] = false;
+            }
+        }
+        self.playing = false;
+    }
+
+    // Send the events of the current period from start to end.
+    // The cursor is the position in the loop at start, and the loop contents are stretched from length to n samples.
+    pub fn play(&mut self, midi : &mut MidiBuffers, start : usize, end : usize, cursor : usize, n : usize, length : usize) {
+        let channel = self.output_channel();
+        if !self.playing {
+            if !self.percussion {
+                midi.send(start, &[0xC0 | channel, self.program]);
+            }
+            self.playing = true;
+        }
+
+        let mut time   = start;
+        let mut cursor = cursor;
+        while time < end {
+            let len = cmp::min(end - time, n - cursor);
+
+            // Send the events between the positions of the cursor, in the loop contents.
+            let first = self.find(scale(cursor, length, n));
+            let last  = self.find(scale(cursor + len, length, n));
+            for k in first .. last {
+                let e = self.events[k];
+                let offset = scale(e.time, n, length).saturating_sub(cursor);
+                let mut bytes = e.event.bytes;
+                bytes[0] = bytes[0] & 0xF0 | channel;
+                if let Some(note) = note_on(&e.event) {
+                    self.held[note] = true;
+                }
+                else if let Some(note) = note_off(&e.event) {
+                    self.held[note] = false;
+                }
+                midi.send(time + cmp::min(offset, len - 1), &bytes[.. e.event.len]);
+            }
+
+            time  += len;
+            cursor = 0;
+        }
+    }
+
+    // Release the notes that are playing.
+    pub fn stop(&mut self, midi : &mut MidiBuffers, time : usize) {
+        let channel = self.output_channel();
+        for note in 0 .. NOTE_COUNT {
+            if self.held[note] {
+                midi.send(time, &[0x80 | channel, note as u8, 0]);
+                self.held[note] = false;
+            }
+        }
+        self.playing = false;
+    }
+
+    // The index of the first event at or after the given position.
+    fn find(&self, position : usize) -> usize {
+        let (mut low, mut high) = (0, self.events.len());
+        while low < high {
+            let mid = (low + high) / 2;
+            if self.events[mid].time < position {
+                low = mid + 1;
+            }
+            else {
+                high = mid;
+            }
+        }
+        low
+    }
+}
+
+// Convert a position in a loop of length from into a position in a loop of length to.
+fn scale(position : usize, to : usize, from : usize) -> usize {
+    (position as u64 * to as u64 / from as u64) as usize
+}
+
+// Notes, controllers and pitch bend are recorded, except the events that control the looper.
+fn is_recordable(event : &MidiEvent) -> bool {
+    match event.bytes[0] & 0xF0 {
+        0x80 | 0x90 | 0xA0 | 0xD0 | 0xE0 => true,
+        0xB0 => event.len == 3 && event.bytes[1] < 120 && !midi314::is_custom_cc(event.bytes[1]),
+        _    => false
+    }
+}
+
+fn note_on(event : &MidiEvent) -> Option<usize> {
+    if event.is_note_on() { Some(event.bytes[1] as usize) } else { None }
+}
+
+fn note_off(event : &MidiEvent) -> Option<usize> {
+    // A note-on event with velocity 0 is a note-off.
+    let status = event.bytes[0] & 0xF0;
+    if event.len == 3 && (status == 0x80 || status == 0x90 && event.bytes[2] == 0) {
+        Some(event.bytes[1] as usize)
+    }
+    else {
+        None
+    }
+}
+
+// MIDI loops are played on channels 2 to 9 and 11,
+// so that they do not conflict with the keyboard on channel 1 and the percussion on channel 10.
+pub fn loop_channel(loop_index : usize) -> u8 {
+    let channel = loop_index as u8 + 1;
+    if channel < PERCUSSION_CHANNEL { channel } else { channel + 1 }
+}
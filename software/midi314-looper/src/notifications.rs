@@ -0,0 +1,85 @@
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use jack;
+
+// The state of the Jack server, as reported by the notification handler.
+// It is shared with the process callback and the control side.
+pub struct ServerStatus {
+    sample_rate : AtomicUsize,
+    buffer_size : AtomicUsize,
+    xruns       : AtomicUsize,
+    latency_changed : AtomicBool,
+    shutdown    : AtomicBool
+}
+
+impl ServerStatus {
+    pub fn new(client : &jack::Client) -> Arc<Self> {
+        Arc::new(Self {
+            sample_rate : AtomicUsize::new(client.sample_rate()),
+            buffer_size : AtomicUsize::new(client.buffer_size() as usize),
+            xruns       : AtomicUsize::new(0),
+            latency_changed : AtomicBool::new(true),
+            shutdown    : AtomicBool::new(false)
+        })
+    }
+
+    pub fn sample_rate(&self) -> usize {
+        self.sample_rate.load(Ordering::Relaxed)
+    }
+
+    pub fn buffer_size(&self) -> usize {
+        self.buffer_size.load(Ordering::Relaxed)
+    }
+
+    pub fn xruns(&self) -> usize {
+        self.xruns.load(Ordering::Relaxed)
+    }
+
+    // Check whether the port latencies have changed since the last call.
+    pub fn take_latency_changed(&self) -> bool {
+        self.latency_changed.swap(false, Ordering::Relaxed)
+    }
+
+    pub fn is_shut_down(&self) -> bool {
+        self.shutdown.load(Ordering::Relaxed)
+    }
+}
+
+pub struct Notifications {
+    status : Arc<ServerStatus>
+}
+
+impl Notifications {
+    pub fn new(status : Arc<ServerStatus>) -> Self {
+        Self {
+            status : status
+        }
+    }
+}
+
+impl jack::NotificationHandler for Notifications {
+    fn shutdown(&mut self, _status : jack::ClientStatus, reason : &str) {
+        eprintln!("The Jack server has shut down: {}", reason);
+        self.status.shutdown.store(true, Ordering::Relaxed);
+    }
+
+    fn buffer_size(&mut self, _ : &jack::Client, size : jack::Frames) -> jack::Control {
+        self.status.buffer_size.store(size as usize, Ordering::Relaxed);
+        jack::Control::Continue
+    }
+
+    fn sample_rate(&mut self, _ : &jack::Client, srate : jack::Frames) -> jack::Control {
+        self.status.sample_rate.store(srate as usize, Ordering::Relaxed);
+        jack::Control::Continue
+    }
+
+    fn xrun(&mut self, _ : &jack::Client) -> jack::Control {
+        self.status.xruns.fetch_add(1, Ordering::Relaxed);
+        jack::Control::Continue
+    }
+
+    fn latency(&mut self, _ : &jack::Client, _mode : jack::LatencyType) {
+        self.status.latency_changed.store(true, Ordering::Relaxed);
+    }
+}
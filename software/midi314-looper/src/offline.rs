@@ -0,0 +1,422 @@
+
+use std::{cmp, process};
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use hound::{SampleFormat, WavReader, WavSpec, WavWriter};
+use midi314::{Keyboard, LoopManager};
+use config::{Config, MAX_CHANNELS};
+use control::{self, MidiEvent};
+use new_looper;
+
+// Run the looper on the contents of a WAV file, with MIDI events from a text file,
+// and write the result to another WAV file.
+// The processing is deterministic, so that it can be used to reproduce and test the looper behavior.
+pub fn render(config : &Config) {
+    let input_file = config.render.as_ref().unwrap();
+    let (inputs, spec) = read_wav(input_file).unwrap_or_else(|e| {
+        eprintln!("Could not read {}: {}", input_file, e);
+        process::exit(1)
+    });
+    if inputs.len() > MAX_CHANNELS {
+        eprintln!("{} has more than {} channels", input_file, MAX_CHANNELS);
+        process::exit(1)
+    }
+
+    let events = match config.events {
+        Some(ref events_file) => read_events(events_file).unwrap_or_else(|e| {
+            eprintln!("Could not read {}: {}", events_file, e);
+            process::exit(1)
+        }),
+        None => Vec::new()
+    };
+
+    // The looper has as many channels as the input file.
+    let mut config = config.clone();
+    config.channels = inputs.len();
+
+    let sample_rate = spec.sample_rate as usize;
+    let mut keyboard = Keyboard::new();
+    let (mut looper, mut allocator) = new_looper(&config, sample_rate, config.buffer_size, keyboard.tempo);
+
+    let (mut controller, mut commands) = control::channel(looper.loops.len(), config.channels, sample_rate);
+
+    let length = inputs[0].len();
+    let mut outputs = vec![vec![0.0 ; length] ; inputs.len()];
+    let mut event_index = 0;
+    let mut start = 0;
+    while start < length {
+        let end = cmp::min(start + config.buffer_size, length);
+
+        // Send the events of the current period to the audio engine.
+        while event_index < events.len() && events[event_index].0 < end {
+            let (time, ref bytes) = events[event_index];
+            if let Some(e) = MidiEvent::new(time as u32, bytes) {
+                if e.is_note_on() {
+                    looper.note_on(time.saturating_sub(start));
+                }
+                looper.midi_event(time.saturating_sub(start), e);
+            }
+            keyboard.update(&mut controller, time, bytes.clone());
+            event_index += 1;
+        }
+        allocator.refill();
+
+        let mut input_slices  : [&[f32] ; MAX_CHANNELS]     = Default::default();
+        let mut output_slices : [&mut [f32] ; MAX_CHANNELS] = Default::default();
+        for (i, input) in input_slices.iter_mut().zip(inputs.iter()) {
+            *i = &input[start .. end];
+        }
+        for (o, output) in output_slices.iter_mut().zip(outputs.iter_mut()) {
+            *o = &mut output[start .. end];
+        }
+
+        looper.process(&mut commands, start as u32, &input_slices[..inputs.len()], &mut output_slices[..inputs.len()]);
+
+        // The control side follows the loop states published by the audio engine.
+        for (i, state) in looper.state_changes.drain(..) {
+            controller.loop_state_changed(i, 0, state);
+        }
+
+        // MIDI loops are not rendered.
+        looper.feedback.clear();
+        looper.midi.output.clear();
+        if let Some(ref mut clock) = looper.clock {
+            clock.output.clear();
+        }
+
+        start = end;
+    }
+
+    if allocator.take_exhausted() {
+        eprintln!("Loop memory is full: a recording has been truncated");
+    }
+
+    write_wav(&config.output, sample_rate as u32, &outputs).unwrap_or_else(|e| {
+        eprintln!("Could not write {}: {}", config.output, e);
+        process::exit(1)
+    });
+}
+
+// Read a WAV file into one buffer per channel.
+fn read_wav(file_name : &str) -> Result<(Vec<Vec<f32>>, WavSpec), String> {
+    let mut reader = WavReader::open(file_name).map_err(|e| e.to_string())?;
+    let spec = reader.spec();
+    let samples : Result<Vec<f32>, _> = match spec.sample_format {
+        SampleFormat::Float => reader.samples::<f32>().collect(),
+        SampleFormat::Int   => {
+            let scale = (1u32 << (spec.bits_per_sample - 1)) as f32;
+            reader.samples::<i32>().map(|x| x.map(|x| x as f32 / scale)).collect()
+        }
+    };
+    let samples = samples.map_err(|e| e.to_string())?;
+
+    let channels = spec.channels as usize;
+    let mut result = vec![Vec::with_capacity(samples.len() / channels) ; channels];
+    for frame in samples.chunks(channels) {
+        for (buffer, x) in result.iter_mut().zip(frame.iter()) {
+            buffer.push(*x);
+        }
+    }
+    Ok((result, spec))
+}
+
+// Write one buffer per channel into a 32-bit float WAV file.
+fn write_wav(file_name : &str, sample_rate : u32, outputs : &[Vec<f32>]) -> Result<(), String> {
+    let spec = WavSpec {
+        channels        : outputs.len() as u16,
+        sample_rate     : sample_rate,
+        bits_per_sample : 32,
+        sample_format   : SampleFormat::Float
+    };
+    let mut writer = WavWriter::create(file_name, spec).map_err(|e| e.to_string())?;
+    for k in 0 .. outputs[0].len() {
+        for output in outputs {
+            writer.write_sample(output[k]).map_err(|e| e.to_string())?;
+        }
+    }
+    writer.finalize().map_err(|e| e.to_string())
+}
+
+// Read MIDI events from a text file.
+// Each line contains a time in samples and the bytes of an event in hexadecimal, for instance:
+//
+//     48000 B0 14 00
+//
+// Empty lines and lines starting with # are ignored.
+fn read_events(file_name : &str) -> Result<Vec<(usize, Vec<u8>)>, String> {
+    let file = File::open(file_name).map_err(|e| e.to_string())?;
+    let mut events = Vec::new();
+    for (n, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.map_err(|e| e.to_string())?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue
+        }
+
+        let mut fields = line.split_whitespace();
+        let time  = fields.next().and_then(|t| t.parse().ok());
+        let bytes : Result<Vec<u8>, _> = fields.map(|b| u8::from_str_radix(b, 16)).collect();
+        match (time, bytes) {
+            (Some(t), Ok(ref b)) if !b.is_empty() => events.push((t, b.clone())),
+            _ => return Err(format!("invalid event at line {}", n + 1))
+        }
+    }
+
+    // Events are processed in chronological order.
+    events.sort_by_key(|e| e.0);
+    Ok(events)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, process};
+    use std::path::PathBuf;
+    use config::Config;
+    use pool::CHUNK_LENGTH;
+    use super::{read_wav, render, write_wav};
+    use mix_ramp_length;
+
+    const SAMPLE_RATE : u32 = 8000;
+
+    // Without fade-in, fade-out, crossfade, pre-roll, monitoring or limiter, loops are played unchanged.
+    const DEFAULT_OPTIONS : [(&str, &str) ; 7] = [
+        ("--buffer-size", "64"), ("--fade-in", "0"), ("--fade-out", "0"), ("--crossfade", "0"), ("--pre-roll", "0"),
+        ("--monitoring", "never"), ("--limiter", "off")
+    ];
+
+    fn temp_file(test : &str, name : &str) -> PathBuf {
+        ::std::env::temp_dir().join(format!("midi314-looper-{}-{}-{}", process::id(), test, name))
+    }
+
+    // Render the given input with the given events, with the default options unless they are in the given options.
+    fn render_events(test : &str, inputs : &[Vec<f32>], events : &str, options : &[&str]) -> Vec<Vec<f32>> {
+        let input_file  = temp_file(test, "input.wav");
+        let events_file = temp_file(test, "events.txt");
+        let output_file = temp_file(test, "output.wav");
+        write_wav(input_file.to_str().unwrap(), SAMPLE_RATE, inputs).unwrap();
+        fs::write(&events_file, events).unwrap();
+
+        let mut args : Vec<&str> = vec![
+            "midi314-looper",
+            "--render", input_file.to_str().unwrap(),
+            "--events", events_file.to_str().unwrap(),
+            "--output", output_file.to_str().unwrap()
+        ];
+        for &(name, value) in DEFAULT_OPTIONS.iter().filter(|o| !options.contains(&o.0)) {
+            args.push(name);
+            args.push(value);
+        }
+        args.extend_from_slice(options);
+        let args : Vec<String> = args.iter().map(|a| a.to_string()).collect();
+        render(&Config::parse(&args));
+
+        let (outputs, spec) = read_wav(output_file.to_str().unwrap()).unwrap();
+        for f in &[input_file, events_file, output_file] {
+            let _ = fs::remove_file(f);
+        }
+        assert_eq!(spec.sample_rate, SAMPLE_RATE);
+        assert_eq!(outputs.len(), inputs.len());
+        assert_eq!(outputs[0].len(), inputs[0].len());
+        outputs
+    }
+
+    // Silence, then a ramp where every sample is different from start to end, then silence.
+    fn ramp_input(start : usize, end : usize, length : usize) -> Vec<Vec<f32>> {
+        vec![(0 .. length).map(|k| {
+            if k >= start && k < end { 0.25 + 0.5 * (k - start) as f32 / (end - start) as f32 } else { 0.0 }
+        }).collect()]
+    }
+
+    // Record the first loop from the first note to the play event, and check that
+    // it plays the input back from its first sample, and wraps around at its end.
+    #[test]
+    fn record_and_play() {
+        let (start, end, length) = (1000, 3000, 8000);
+        let inputs  = ramp_input(start, end, length);
+        let outputs = render_events("record_and_play", &inputs, &format!("0 B0 14 00\n{} B0 15 00\n", end), &[]);
+
+        for (k, &x) in outputs[0].iter().enumerate() {
+            if k < end {
+                assert_eq!(x, 0.0, "sample {} before the playback", k);
+            }
+            else {
+                assert_eq!(x, inputs[0][start + (k - end) % (end - start)], "sample {} of the playback", k);
+            }
+        }
+    }
+
+    // Start the first loop when the input rises above the threshold, in the middle of a period,
+    // with periods that divide neither the start nor the length of the loop.
+    #[test]
+    fn threshold_trigger() {
+        let (start, end, length) = (1037, 2990, 9000);
+        let mut inputs = ramp_input(start, end, length);
+        assert!(!start.is_multiple_of(100) && !(end - start).is_multiple_of(100));
+
+        // Noise below the threshold of -40 dBFS is not recorded.
+        for (k, x) in inputs[0][.. start].iter_mut().enumerate() {
+            *x = if k % 2 == 0 { 0.005 } else { -0.005 };
+        }
+        let events  = format!("0 B0 14 00\n{} B0 15 00\n", end);
+        let outputs = render_events("threshold_trigger", &inputs, &events, &["--buffer-size", "100"]);
+
+        for (k, &x) in outputs[0].iter().enumerate() {
+            let y = if k < end { 0.0 } else { inputs[0][start + (k - end) % (end - start)] };
+            assert_eq!(x, y, "sample {}", k);
+        }
+    }
+
+    // Record a second loop while the first one plays, across the end of the master loop.
+    // The second loop lasts two master loops, with the recording at its position in the master loop
+    // and silence before and after it.
+    #[test]
+    fn second_loop_wraps() {
+        let (start, end) = (1000, 3000);
+        let master_length = end - start;
+        let (second, second_end) = (end + 500, end + 500 + master_length);
+        let length = end + 5 * master_length;
+
+        // The second loop records a falling ramp.
+        let mut inputs = ramp_input(start, end, length);
+        for k in second .. second_end {
+            inputs[0][k] = -0.5 * inputs[0][k - second + start];
+        }
+        let events  = format!("0 B0 14 00\n{} B0 15 00\n{} B0 14 01\n{} B0 15 01\n", end, second, second_end);
+        let outputs = render_events("second_loop_wraps", &inputs, &events, &[]);
+
+        for (k, &x) in outputs[0].iter().enumerate() {
+            if k < end {
+                assert_eq!(x, 0.0, "sample {} before the playback", k);
+                continue
+            }
+            let first = inputs[0][start + (k - end) % master_length];
+            let p = (k - end) % (2 * master_length);
+            let y = if k >= second_end && p >= second - end && p < second_end - end { first + inputs[0][end + p] } else { first };
+            assert!((x - y).abs() < 1.0e-6, "sample {}: {} instead of {}", k, x, y);
+        }
+    }
+
+    // Record the first loop until its buffer is full, so that the audio engine plays it by itself.
+    // The control side follows its state: a scene saved then plays it again after it has been muted.
+    #[test]
+    fn scene_after_full_recording() {
+        // With a maximum length of 1 s, the loop buffer has one chunk,
+        // from the beginning of the period where the recording starts.
+        let start = 1000;
+        let full  = start - start % 64 + CHUNK_LENGTH;
+        let master_length = full - start;
+        let length  = full + 2 * master_length;
+        let inputs  = ramp_input(start, full, length);
+        let events  = format!("0 B0 14 00\n{} B0 0F 00\n{} B0 16 00\n{} B0 58 00\n", full + 100, full + 200, full + 300);
+        let outputs = render_events("scene_after_full_recording", &inputs, &events, &["--max-length", "1"]);
+
+        let boundary = full + master_length;
+        for (k, &x) in outputs[0].iter().enumerate() {
+            let y = if k < full || (k >= full + 200 && k < boundary) { 0.0 } else { inputs[0][start + (k - full) % master_length] };
+            assert_eq!(x, y, "sample {}", k);
+        }
+    }
+
+    // Save a scene while the first loop plays, then mute the loop and set its gain to 0,
+    // and recall the scene: the loop plays again from the next beginning of the master loop,
+    // where its gain starts moving back to the gain of the scene.
+    fn recall_scene(test : &str, start : usize, end : usize) -> usize {
+        let master_length = end - start;
+        let length  = end + 3 * master_length;
+        let inputs  = ramp_input(start, end, length);
+        let events  = format!("0 B0 14 00\n{} B0 15 00\n{} B0 0F 00\n{} B0 66 00\n{} B0 16 00\n{} B0 58 00\n",
+                              end, end + 100, end + 200, end + 400, end + 500);
+        let outputs = render_events(test, &inputs, &events, &[]);
+
+        let boundary = end + master_length;
+        for (k, &x) in outputs[0].iter().enumerate() {
+            if k < end {
+                assert_eq!(x, 0.0, "sample {} before the playback", k);
+                continue
+            }
+            let y = inputs[0][start + (k - end) % master_length];
+            if k < end + 200 {
+                assert_eq!(x, y, "sample {} of the playback", k);
+            }
+            else if k >= end + 400 && k < boundary {
+                assert_eq!(x, 0.0, "sample {} of the muted loop", k);
+            }
+            else if k >= boundary {
+                let gain = ((k - boundary + 1) as f32 / mix_ramp_length(SAMPLE_RATE as usize) as f32).min(1.0);
+                assert!((x - gain * y).abs() < 1.0e-4, "sample {} after the recall: {} instead of {}", k, x, gain * y);
+            }
+        }
+        boundary
+    }
+
+    #[test]
+    fn recall_scene_within_period() {
+        let boundary = recall_scene("recall_scene_within_period", 1000, 3000);
+        assert!(!boundary.is_multiple_of(64));
+    }
+
+    #[test]
+    fn recall_scene_at_period_start() {
+        let boundary = recall_scene("recall_scene_at_period_start", 1024, 3072);
+        assert!(boundary.is_multiple_of(64));
+    }
+
+    // Record the first loop twice, then undo the second recording and redo it.
+    // The recordings are crossfaded over the fade-out, from the time of the undo and of the redo.
+    #[test]
+    fn undo_and_redo() {
+        let (start, end) = (1000, 3000);
+        let master_length = end - start;
+        let (second, second_end) = (end + master_length, end + 2 * master_length);
+        let (undo, redo) = (second_end + 507, second_end + master_length + 903);
+        let length = end + 5 * master_length;
+
+        // The second recording is a falling ramp.
+        let mut inputs = ramp_input(start, end, length);
+        for k in second .. second_end {
+            inputs[0][k] = -inputs[0][k - second + start];
+        }
+        let events = format!("0 B0 14 00\n{} B0 15 00\n{} B0 14 00\n{} B0 15 00\n{} B0 1D 00\n{} B0 1E 00\n",
+                             end, second, second_end, undo, redo);
+        let outputs = render_events("undo_and_redo", &inputs, &events, &["--fade-out", "1"]);
+
+        // The end of each recording fades out.
+        let fade = 8;
+        let recording = |p : usize, offset : usize| -> f32 {
+            let gain = ((master_length - 1 - p) as f32 / fade as f32).min(1.0);
+            gain * inputs[0][offset + p]
+        };
+        for (k, &x) in outputs[0].iter().enumerate() {
+            if k < end {
+                assert_eq!(x, 0.0, "sample {} before the playback", k);
+                continue
+            }
+            let p = (k - end) % master_length;
+            let (first, second_take) = (recording(p, start), recording(p, second));
+            let y = if k < second {
+                first
+            }
+            else if k < second_end {
+                continue
+            }
+            else if k < undo {
+                second_take
+            }
+            else if k < undo + fade {
+                let j = (k - undo) as f32;
+                (fade as f32 - j) / fade as f32 * second_take + (j + 1.0) / fade as f32 * first
+            }
+            else if k < redo {
+                first
+            }
+            else if k < redo + fade {
+                let j = (k - redo) as f32;
+                (fade as f32 - j) / fade as f32 * first + (j + 1.0) / fade as f32 * second_take
+            }
+            else {
+                second_take
+            };
+            assert!((x - y).abs() < 1.0e-5, "sample {}: {} instead of {}", k, x, y);
+        }
+    }
+}
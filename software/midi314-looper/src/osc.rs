@@ -0,0 +1,264 @@
+
+use std::str;
+
+// The arguments of OSC messages supported by the looper.
+// Other argument types are skipped when decoding.
+#[derive(Clone, PartialEq)]
+pub enum OscArg {
+    Int(i32),
+    Float(f32),
+    Str(String),
+    Bool(bool)
+}
+
+impl OscArg {
+    // The numeric value of an argument, if any.
+    pub fn to_f32(&self) -> Option<f32> {
+        match *self {
+            OscArg::Int(n)   => Some(n as f32),
+            OscArg::Float(x) => Some(x),
+            OscArg::Bool(b)  => Some(if b { 1.0 } else { 0.0 }),
+            OscArg::Str(_)   => None
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct OscMessage {
+    pub address : String,
+    pub args    : Vec<OscArg>
+}
+
+impl OscMessage {
+    pub fn new(address : &str, args : Vec<OscArg>) -> Self {
+        Self {
+            address : address.to_string(),
+            args    : args
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        write_string(&mut bytes, &self.address);
+
+        let mut tags = String::from(",");
+        for a in &self.args {
+            tags.push(match *a {
+                OscArg::Int(_)      => 'i',
+                OscArg::Float(_)    => 'f',
+                OscArg::Str(_)      => 's',
+                OscArg::Bool(true)  => 'T',
+                OscArg::Bool(false) => 'F'
+            });
+        }
+        write_string(&mut bytes, &tags);
+
+        for a in &self.args {
+            match *a {
+                OscArg::Int(n)     => bytes.extend_from_slice(&n.to_be_bytes()),
+                OscArg::Float(x)   => bytes.extend_from_slice(&x.to_bits().to_be_bytes()),
+                OscArg::Str(ref s) => write_string(&mut bytes, s),
+                OscArg::Bool(_)    => {}
+            }
+        }
+        bytes
+    }
+}
+
+// Decode the messages of an OSC packet.
+// The messages of a bundle are returned in order, and their time tags are ignored.
+pub fn decode(packet : &[u8]) -> Result<Vec<OscMessage>, String> {
+    let mut messages = Vec::new();
+    decode_packet(packet, &mut messages)?;
+    Ok(messages)
+}
+
+fn decode_packet(packet : &[u8], messages : &mut Vec<OscMessage>) -> Result<(), String> {
+    let mut reader = Reader { bytes : packet, position : 0 };
+    let address = reader.string()?;
+    if address == "#bundle" {
+        // Skip the time tag, then decode each element with its size.
+        reader.take(8)?;
+        while !reader.is_empty() {
+            let size = reader.int()?;
+            if size < 0 {
+                return Err(String::from("invalid bundle element size"))
+            }
+            let element = reader.take(size as usize)?;
+            decode_packet(element, messages)?;
+        }
+        return Ok(())
+    }
+    if !address.starts_with('/') {
+        return Err(format!("invalid address {}", address))
+    }
+
+    // Messages without a type tag string have no arguments.
+    let tags = if reader.is_empty() { String::from(",") } else { reader.string()? };
+    if !tags.starts_with(',') {
+        return Err(format!("invalid type tags {}", tags))
+    }
+
+    let mut args = Vec::new();
+    for t in tags[1..].chars() {
+        match t {
+            'i' => args.push(OscArg::Int(reader.int()?)),
+            'f' => args.push(OscArg::Float(f32::from_bits(reader.int()? as u32))),
+            'd' => args.push(OscArg::Float(f64::from_bits(reader.long()? as u64) as f32)),
+            'h' => args.push(OscArg::Int(reader.long()? as i32)),
+            's' | 'S' => args.push(OscArg::Str(reader.string()?)),
+            'T' => args.push(OscArg::Bool(true)),
+            'F' => args.push(OscArg::Bool(false)),
+            'b' => {
+                let size = reader.int()?;
+                if size < 0 {
+                    return Err(String::from("invalid blob size"))
+                }
+                reader.take(size as usize)?;
+                reader.align()?;
+            },
+            't' => { reader.take(8)?; },
+            'c' | 'r' | 'm' => { reader.take(4)?; },
+            'N' | 'I' | '[' | ']' => {},
+            _ => return Err(format!("unsupported type tag {}", t))
+        }
+    }
+    messages.push(OscMessage { address : address, args : args });
+    Ok(())
+}
+
+// Strings are null-terminated and padded to a multiple of 4 bytes.
+fn write_string(bytes : &mut Vec<u8>, s : &str) {
+    bytes.extend_from_slice(s.as_bytes());
+    let padding = 4 - s.len() % 4;
+    bytes.extend(std::iter::repeat_n(0, padding));
+}
+
+struct Reader<'a> {
+    bytes    : &'a [u8],
+    position : usize
+}
+
+impl<'a> Reader<'a> {
+    fn is_empty(&self) -> bool {
+        self.position >= self.bytes.len()
+    }
+
+    fn take(&mut self, len : usize) -> Result<&'a [u8], String> {
+        if len > self.bytes.len() - self.position {
+            return Err(String::from("truncated packet"))
+        }
+        let result = &self.bytes[self.position .. self.position + len];
+        self.position += len;
+        Ok(result)
+    }
+
+    fn align(&mut self) -> Result<(), String> {
+        let padding = (4 - self.position % 4) % 4;
+        self.take(padding).map(|_| ())
+    }
+
+    fn int(&mut self) -> Result<i32, String> {
+        let b = self.take(4)?;
+        Ok(i32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn long(&mut self) -> Result<i64, String> {
+        let b = self.take(8)?;
+        Ok(i64::from_be_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]))
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let rest = &self.bytes[self.position ..];
+        let len = rest.iter().position(|&b| b == 0).ok_or_else(|| String::from("unterminated string"))?;
+        let s = str::from_utf8(&rest[..len]).map_err(|e| e.to_string())?.to_string();
+        self.take(len + 1)?;
+        self.align()?;
+        Ok(s)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, write_string, OscArg, OscMessage};
+
+    fn bundle(elements : &[Vec<u8>]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        write_string(&mut bytes, "#bundle");
+        bytes.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 1]);
+        for e in elements {
+            bytes.extend_from_slice(&(e.len() as i32).to_be_bytes());
+            bytes.extend_from_slice(e);
+        }
+        bytes
+    }
+
+    #[test]
+    fn message_round_trip() {
+        let args = vec![OscArg::Int(-3), OscArg::Float(0.5), OscArg::Str(String::from("half")), OscArg::Bool(true)];
+        let bytes = OscMessage::new("/loop/1/speed", args.clone()).to_bytes();
+        assert!(bytes.len().is_multiple_of(4));
+        let messages = decode(&bytes).unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].address, "/loop/1/speed");
+        assert!(messages[0].args == args);
+    }
+
+    #[test]
+    fn bundles() {
+        let record = OscMessage::new("/loop/1/record", vec![]).to_bytes();
+        let gain   = OscMessage::new("/loop/2/gain", vec![OscArg::Int(100)]).to_bytes();
+        let tempo  = OscMessage::new("/tempo", vec![OscArg::Float(90.0)]).to_bytes();
+        let packet = bundle(&[record, bundle(&[gain, tempo])]);
+
+        // The messages of nested bundles are returned in order.
+        let messages = decode(&packet).unwrap();
+        let addresses : Vec<&str> = messages.iter().map(|m| m.address.as_str()).collect();
+        assert_eq!(addresses, vec!["/loop/1/record", "/loop/2/gain", "/tempo"]);
+        assert!(messages[1].args == vec![OscArg::Int(100)]);
+        assert!(messages[2].args == vec![OscArg::Float(90.0)]);
+
+        assert!(decode(&bundle(&[])).unwrap().is_empty());
+    }
+
+    #[test]
+    fn truncated_packets() {
+        let message = OscMessage::new("/loop/3/pan", vec![OscArg::Int(64), OscArg::Str(String::from("abc"))]).to_bytes();
+        // A message without type tags, after the 12 bytes of the address, is valid,
+        // but any other truncation is an error.
+        for len in 0 .. message.len() {
+            let result = decode(&message[..len]);
+            if len == 12 {
+                assert!(result.unwrap()[0].args.is_empty());
+            }
+            else {
+                assert!(result.is_err(), "{} bytes", len);
+            }
+        }
+
+        // The size of a bundle element is larger than the rest of the packet.
+        let mut packet = bundle(&[message]);
+        packet.truncate(packet.len() - 4);
+        assert!(decode(&packet).is_err());
+        // The time tag of a bundle is missing.
+        assert!(decode(&bundle(&[])[..12]).is_err());
+        // Negative bundle element size.
+        let mut packet = bundle(&[]);
+        packet.extend_from_slice(&(-4i32).to_be_bytes());
+        assert!(decode(&packet).is_err());
+    }
+
+    #[test]
+    fn invalid_packets() {
+        let mut bytes = Vec::new();
+        write_string(&mut bytes, "loop");
+        write_string(&mut bytes, ",");
+        assert!(decode(&bytes).is_err());
+
+        let mut bytes = Vec::new();
+        write_string(&mut bytes, "/loop/1/gain");
+        write_string(&mut bytes, ",x");
+        bytes.extend_from_slice(&[0 ; 4]);
+        assert!(decode(&bytes).is_err());
+    }
+}
@@ -0,0 +1,171 @@
+
+use std::cmp;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use ringbuf::{Consumer, Producer, RingBuffer};
+
+// The number of samples per channel in a chunk of loop memory.
+// This is a power of two so that sample indices are split with shifts and masks.
+const CHUNK_BITS   : usize = 14;
+pub const CHUNK_LENGTH : usize = 1 << CHUNK_BITS;
+
+// The number of new chunks that the control side keeps ready for the audio engine.
+const RESERVE : usize = 16;
+
+// A block of loop memory, with one buffer for each audio channel.
+pub type Chunk = Vec<Vec<f32>>;
+
+// Create a pool of loop memory that can hold the given number of samples per channel,
+// shared by all loops and undo levels.
+// Memory is allocated on the control side, and given to the audio engine when needed.
+pub fn pool(channels : usize, length : usize) -> (ChunkAllocator, ChunkPool) {
+    let count = length.div_ceil(CHUNK_LENGTH);
+    let (producer, consumer) = RingBuffer::new(count.clamp(1, RESERVE)).split();
+    let exhausted = Arc::new(AtomicBool::new(false));
+    let allocator = ChunkAllocator {
+        channels  : channels,
+        remaining : count,
+        chunks    : producer,
+        exhausted : exhausted.clone()
+    };
+    let pool = ChunkPool {
+        free      : Vec::with_capacity(count),
+        chunks    : consumer,
+        exhausted : exhausted
+    };
+    (allocator, pool)
+}
+
+// The control side of the memory pool.
+pub struct ChunkAllocator {
+    channels  : usize,
+    remaining : usize,
+    chunks    : Producer<Chunk>,
+    exhausted : Arc<AtomicBool>
+}
+
+impl ChunkAllocator {
+    // Allocate new chunks until the reserve is full or the memory limit is reached.
+    pub fn refill(&mut self) {
+        while self.remaining > 0 && !self.chunks.is_full() {
+            let _ = self.chunks.push(vec![vec![0.0 ; CHUNK_LENGTH] ; self.channels]);
+            self.remaining -= 1;
+        }
+    }
+
+    // Check whether a recording has been truncated because the pool was empty.
+    pub fn take_exhausted(&self) -> bool {
+        self.exhausted.swap(false, Ordering::Relaxed)
+    }
+}
+
+// The audio engine side of the memory pool.
+// Chunks released by the loops are kept for reuse, and are never deallocated.
+pub struct ChunkPool {
+    free      : Vec<Chunk>,
+    chunks    : Consumer<Chunk>,
+    exhausted : Arc<AtomicBool>
+}
+
+impl ChunkPool {
+    fn acquire(&mut self) -> Option<Chunk> {
+        // Reused chunks are cleared here rather than when they are released,
+        // to spread the cost of deleting a long loop over time.
+        if let Some(mut chunk) = self.free.pop() {
+            for samples in &mut chunk {
+                for x in samples.iter_mut() {
+                    *x = 0.0;
+                }
+            }
+            return Some(chunk)
+        }
+        let chunk = self.chunks.pop();
+        if chunk.is_none() {
+            self.exhausted.store(true, Ordering::Relaxed);
+        }
+        chunk
+    }
+
+    fn release(&mut self, chunk : Chunk) {
+        // The free list has room for all the chunks of the pool.
+        self.free.push(chunk);
+    }
+}
+
+// The memory of a loop, divided in chunks that are taken from the pool when recording.
+// Samples that have never been recorded read as silence.
+#[derive(Clone)]
+pub struct LoopBuffer {
+    chunks : Vec<Option<Chunk>>
+}
+
+impl LoopBuffer {
+    pub fn new(length : usize) -> Self {
+        Self {
+            chunks : vec![None ; length.div_ceil(CHUNK_LENGTH)]
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.chunks.len() * CHUNK_LENGTH
+    }
+
+    pub fn get(&self, channel : usize, index : usize) -> f32 {
+        match self.chunks[index >> CHUNK_BITS] {
+            Some(ref chunk) => chunk[channel][index & (CHUNK_LENGTH - 1)],
+            None            => 0.0
+        }
+    }
+
+    pub fn get_mut(&mut self, channel : usize, index : usize) -> Option<&mut f32> {
+        match self.chunks[index >> CHUNK_BITS] {
+            Some(ref mut chunk) => Some(&mut chunk[channel][index & (CHUNK_LENGTH - 1)]),
+            None                => None
+        }
+    }
+
+    // Get a sample for writing, taking a chunk from the pool if needed.
+    pub fn get_mut_or_acquire(&mut self, pool : &mut ChunkPool, channel : usize, index : usize) -> Option<&mut f32> {
+        let slot = &mut self.chunks[index >> CHUNK_BITS];
+        if slot.is_none() {
+            *slot = pool.acquire();
+        }
+        match *slot {
+            Some(ref mut chunk) => Some(&mut chunk[channel][index & (CHUNK_LENGTH - 1)]),
+            None                => None
+        }
+    }
+
+    // Copy samples from the inputs to the given position in this buffer.
+    // Samples are dropped if the pool has no memory left.
+    pub fn write(&mut self, pool : &mut ChunkPool, index : usize, inputs : &[&[f32]], start : usize, end : usize) {
+        let mut index = index;
+        let mut start = start;
+        while start < end {
+            let offset = index & (CHUNK_LENGTH - 1);
+            let len    = cmp::min(end - start, CHUNK_LENGTH - offset);
+
+            let slot = &mut self.chunks[index >> CHUNK_BITS];
+            if slot.is_none() {
+                *slot = pool.acquire();
+            }
+            if let Some(ref mut chunk) = *slot {
+                for (samples, input) in chunk.iter_mut().zip(inputs.iter()) {
+                    samples[offset .. offset + len].copy_from_slice(&input[start .. start + len]);
+                }
+            }
+
+            index += len;
+            start += len;
+        }
+    }
+
+    // Give all the chunks of this buffer back to the pool.
+    pub fn release(&mut self, pool : &mut ChunkPool) {
+        for slot in &mut self.chunks {
+            if let Some(chunk) = slot.take() {
+                pool.release(chunk);
+            }
+        }
+    }
+}
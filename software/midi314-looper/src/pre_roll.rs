@@ -0,0 +1,61 @@
+
+use std::cmp;
+
+// A rolling buffer of the most recent input samples.
+// When the first loop starts recording, its content is prepended to the loop,
+// so that the attack of the first note is not lost.
+pub struct PreRoll {
+    history  : Vec<Vec<f32>>,
+    position : usize,
+    samples  : Vec<Vec<f32>>
+}
+
+impl PreRoll {
+    pub fn new(channels : usize, length : usize) -> Self {
+        Self {
+            history  : vec![vec![0.0 ; length] ; channels],
+            position : 0,
+            samples  : vec![vec![0.0 ; length] ; channels]
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples[0].len()
+    }
+
+    pub fn samples(&self) -> &[Vec<f32>] {
+        &self.samples
+    }
+
+    // Keep the end of the given input buffers.
+    pub fn push(&mut self, inputs : &[&[f32]]) {
+        let length = self.len();
+        if length == 0 {
+            return
+        }
+        let in_len = inputs[0].len();
+        let start  = in_len.saturating_sub(length);
+        for (history, input) in self.history.iter_mut().zip(inputs.iter()) {
+            let mut position = self.position;
+            for x in &input[start ..] {
+                history[position] = *x;
+                position = (position + 1) % length;
+            }
+        }
+        self.position = (self.position + in_len - start) % length;
+    }
+
+    // Collect the samples that precede the given position in the current input buffers.
+    pub fn capture(&mut self, inputs : &[&[f32]], end : usize) {
+        let length = self.len();
+        let from_inputs  = cmp::min(end, length);
+        let from_history = length - from_inputs;
+        for ((samples, history), input) in self.samples.iter_mut().zip(self.history.iter()).zip(inputs.iter()) {
+            // The oldest samples come from the history, the most recent from the current buffers.
+            for (k, x) in samples[.. from_history].iter_mut().enumerate() {
+                *x = history[(self.position + length - from_history + k) % length];
+            }
+            samples[from_history ..].copy_from_slice(&input[end - from_inputs .. end]);
+        }
+    }
+}
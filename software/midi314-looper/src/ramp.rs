@@ -0,0 +1,106 @@
+
+use std::cmp;
+
+// A parameter that moves linearly to its target value,
+// to avoid audible steps when the value changes.
+#[derive(Clone, Copy)]
+pub struct Ramp {
+    value  : f32,
+    target : f32,
+    step   : f32,
+    // The number of samples before the value starts moving.
+    delay  : usize
+}
+
+impl Ramp {
+    pub fn new(value : f32) -> Self {
+        Self {
+            value  : value,
+            target : value,
+            step   : 0.0,
+            delay  : 0
+        }
+    }
+
+    pub fn set(&mut self, target : f32, length : usize) {
+        self.set_at(0, target, length);
+    }
+
+    // Start moving to the target value at the given time in the current period.
+    pub fn set_at(&mut self, time : usize, target : f32, length : usize) {
+        self.target = target;
+        self.step   = (target - self.value) / cmp::max(length, 1) as f32;
+        self.delay  = time;
+    }
+
+    pub fn reset(&mut self, value : f32) {
+        self.value  = value;
+        self.target = value;
+        self.delay  = 0;
+    }
+
+    pub fn finish(&mut self) {
+        self.value = self.target;
+        self.delay = 0;
+    }
+
+    pub fn value(&self) -> f32 {
+        self.value
+    }
+
+    pub fn target(&self) -> f32 {
+        self.target
+    }
+
+    pub fn next(&mut self) -> f32 {
+        self.skip(1);
+        self.value
+    }
+
+    // Move the value by the given number of samples.
+    pub fn skip(&mut self, len : usize) {
+        let delay = cmp::min(self.delay, len);
+        self.delay -= delay;
+        if len > delay && self.value != self.target {
+            self.value += self.step * (len - delay) as f32;
+            // Stop at the target value.
+            if (self.step >= 0.0) == (self.value >= self.target) {
+                self.value = self.target;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Ramp;
+
+    #[test]
+    fn endpoints() {
+        let mut r = Ramp::new(0.0);
+        r.set(1.0, 4);
+        assert_eq!(r.value(), 0.0);
+        let values : Vec<f32> = (0 .. 6).map(|_| r.next()).collect();
+        assert_eq!(values, vec![0.25, 0.5, 0.75, 1.0, 1.0, 1.0]);
+
+        // Going down, the ramp stops exactly at the target.
+        r.set(-0.5, 3);
+        r.skip(10);
+        assert_eq!(r.value(), -0.5);
+    }
+
+    #[test]
+    fn delayed_start() {
+        let mut r = Ramp::new(1.0);
+        r.set_at(2, 0.0, 2);
+        let values : Vec<f32> = (0 .. 5).map(|_| r.next()).collect();
+        assert_eq!(values, vec![1.0, 1.0, 0.5, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn zero_length() {
+        let mut r = Ramp::new(0.0);
+        r.set(0.8, 0);
+        assert_eq!(r.next(), 0.8);
+    }
+}
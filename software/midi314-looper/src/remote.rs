@@ -0,0 +1,327 @@
+
+use std::io::ErrorKind;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use midi314::{Effect, Keyboard, LoopManager, LoopSpeed, LoopState};
+use control::Controller;
+use meter::{amplitude_to_db, Level, Levels};
+use osc::{self, OscArg, OscMessage};
+use session::{self, Session};
+
+// The maximum size of the OSC packets received.
+const MAX_PACKET_SIZE : usize = 4096;
+
+// The maximum number of clients that receive notifications.
+const MAX_CLIENTS : usize = 16;
+
+// The parameters of a loop that are notified to the clients when they change.
+#[derive(Clone, Copy, PartialEq)]
+struct LoopStatus {
+    state   : LoopState,
+    gain    : u8,
+    pan     : u8,
+    reverse : bool,
+    speed   : LoopSpeed
+}
+
+// A server that controls the looper with OSC messages over UDP.
+//
+// Loops are numbered from 1. Messages that trigger an action, such as /loop/1/record,
+// are ignored when their first argument is 0, so that buttons can send their value on press and release.
+//
+//     /loop/N/record, /loop/N/play, /loop/N/mute, /loop/N/delete, /loop/N/solo
+//     /loop/N/undo, /loop/N/redo
+//     /loop/N/gain VALUE, /loop/N/pan VALUE   (0 to 127, or 0.0 to 1.0)
+//     /loop/N/reverse [0|1], /loop/N/speed normal|half|double
+//     /loop/N/program
+//     /loop/N/effect/EFFECT/PARAM VALUE, /master/effect/EFFECT/PARAM VALUE
+//         (for instance /loop/1/effect/delay/time 64 or /master/effect/reverb/mix 0.5)
+//     /scene/N/save, /scene/N/recall
+//     /all, /tempo BPM, /session/save, /session/load
+//     /register, /unregister
+//
+// Notifications are sent to the address from which /register was received.
+// Registered clients receive the changes as /loop/N/state, /loop/N/gain, /loop/N/pan,
+// /loop/N/reverse, /loop/N/speed and /tempo messages.
+// They also receive the levels as /meter/input, /meter/master and /loop/N/meter messages,
+// with the peak and RMS levels in dBFS and 1 if the signal reached full scale.
+pub struct OscServer {
+    socket   : UdpSocket,
+    clients  : Vec<SocketAddr>,
+    loops    : Vec<Option<LoopStatus>>,
+    tempo    : Option<u32>,
+    session  : Option<String>,
+    buffer   : Vec<u8>
+}
+
+impl OscServer {
+    pub fn new(address : IpAddr, port : u16, session : Option<String>) -> Result<Self, String> {
+        let socket = UdpSocket::bind((address, port)).map_err(|e| e.to_string())?;
+        socket.set_nonblocking(true).map_err(|e| e.to_string())?;
+        Ok(Self {
+            socket  : socket,
+            clients : Vec::new(),
+            loops   : Vec::new(),
+            tempo   : None,
+            session : session,
+            buffer  : vec![0 ; MAX_PACKET_SIZE]
+        })
+    }
+
+    // Process the messages received since the last call, at the given time,
+    // and notify the registered clients of the changes.
+    pub fn poll(&mut self, keyboard : &mut Keyboard, controller : &mut Controller, time : usize) {
+        loop {
+            let (len, source) = match self.socket.recv_from(&mut self.buffer) {
+                Ok(r) => r,
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    eprintln!("Could not receive OSC message: {}", e);
+                    break
+                }
+            };
+            match osc::decode(&self.buffer[..len]) {
+                Ok(messages) => for m in messages {
+                    self.handle(keyboard, controller, time, source, &m);
+                },
+                Err(e) => eprintln!("Invalid OSC packet from {}: {}", source, e)
+            }
+        }
+        self.notify(keyboard, controller);
+    }
+
+    fn handle(&mut self, keyboard : &mut Keyboard, controller : &mut Controller, time : usize, source : SocketAddr, m : &OscMessage) {
+        let path : Vec<&str> = m.address[1..].split('/').collect();
+        let value = m.args.first().and_then(|a| a.to_f32());
+        let cc    = m.args.first().and_then(cc_value);
+        // Actions are not triggered when a button is released.
+        let trigger = value.is_none_or(|v| v != 0.0);
+
+        match path.as_slice() {
+            ["loop", n, command] => {
+                let index = match n.parse::<usize>() {
+                    Ok(n) if (1 ..= controller.get_loop_count()).contains(&n) => n - 1,
+                    _ => {
+                        eprintln!("Invalid loop number in OSC address {}", m.address);
+                        return
+                    }
+                };
+                match *command {
+                    "record"  if trigger => controller.set_loop_state(index, time, LoopState::Recording),
+                    "play"    if trigger => controller.set_loop_state(index, time, LoopState::Playing),
+                    "mute"    if trigger => controller.set_loop_state(index, time, LoopState::Muted),
+                    "delete"  if trigger => controller.set_loop_state(index, time, LoopState::Empty),
+                    "solo"    if trigger => controller.play_solo(index, time),
+                    "undo"    if trigger => controller.undo(index, time),
+                    "redo"    if trigger => controller.redo(index, time),
+                    "program" if trigger => controller.set_loop_program(index, time, keyboard.current_program as u8),
+                    "gain"    => if let Some(g) = cc {
+                        controller.set_loop_gain(index, time, g)
+                    },
+                    "pan"     => if let Some(p) = cc {
+                        controller.set_loop_pan(index, time, p)
+                    },
+                    "reverse" => match value {
+                        Some(v) => controller.set_loop_reverse(index, time, v != 0.0),
+                        None    => controller.toggle_reverse(index, time)
+                    },
+                    "speed"   => match m.args.first() {
+                        Some(OscArg::Str(s)) => match session::parse_speed(s) {
+                            Some(speed) => controller.set_loop_speed(index, time, speed),
+                            None        => eprintln!("Invalid loop speed in OSC message: {}", s)
+                        },
+                        _ => eprintln!("Missing loop speed in OSC message {}", m.address)
+                    },
+                    _ => {}
+                }
+            },
+            ["loop", n, "effect", effect, param] => match n.parse::<usize>() {
+                Ok(n) if (1 ..= controller.get_loop_count()).contains(&n) =>
+                    set_effect_param(controller, time, n - 1, effect, param, cc),
+                _ => eprintln!("Invalid loop number in OSC address {}", m.address)
+            },
+            ["master", "effect", effect, param] => {
+                let target = controller.get_loop_count();
+                set_effect_param(controller, time, target, effect, param, cc)
+            },
+            ["scene", n, command] => {
+                let index = match n.parse::<usize>() {
+                    Ok(n) if n >= 1 => n - 1,
+                    _ => {
+                        eprintln!("Invalid scene number in OSC address {}", m.address);
+                        return
+                    }
+                };
+                match *command {
+                    "save"   if trigger => controller.save_scene(index, time),
+                    "recall" if trigger => controller.recall_scene(index, time),
+                    _ => {}
+                }
+            },
+            ["all"]   => if trigger {
+                controller.play_all(time)
+            },
+            ["tempo"] => if let Some(v) = value {
+                keyboard.set_tempo(controller, time, v.round() as u32)
+            },
+            ["session", "save"] => if trigger {
+                self.save_session(keyboard, controller)
+            },
+            ["session", "load"] => if trigger {
+                self.load_session(keyboard, controller, time)
+            },
+            ["register"]   => self.register(source),
+            ["unregister"] => self.clients.retain(|&c| c != source),
+            _ => eprintln!("Unknown OSC address {}", m.address)
+        }
+    }
+
+    fn save_session(&self, keyboard : &Keyboard, controller : &Controller) {
+        match self.session {
+            Some(ref file_name) => if let Err(e) = Session::capture(keyboard, controller).save(file_name) {
+                eprintln!("Could not write {}: {}", file_name, e);
+            },
+            None => eprintln!("Cannot save the session: no session file was configured")
+        }
+    }
+
+    fn load_session(&self, keyboard : &mut Keyboard, controller : &mut Controller, time : usize) {
+        match self.session {
+            Some(ref file_name) => match Session::load(file_name, &Session::capture(keyboard, controller)) {
+                Ok(s)  => s.restore(keyboard, controller, time),
+                Err(e) => eprintln!("Could not read {}: {}", file_name, e)
+            },
+            None => eprintln!("Cannot load the session: no session file was configured")
+        }
+    }
+
+    // Register the sender as a client.
+    // A new client receives the current state of all loops.
+    fn register(&mut self, client : SocketAddr) {
+        if self.clients.contains(&client) {
+            return
+        }
+        if self.clients.len() == MAX_CLIENTS {
+            eprintln!("Too many OSC clients: {} is not registered", client);
+            return
+        }
+        self.clients.push(client);
+        self.loops.clear();
+        self.tempo = None;
+    }
+
+    fn notify(&mut self, keyboard : &Keyboard, controller : &Controller) {
+        if self.clients.is_empty() {
+            return
+        }
+
+        let mut messages = Vec::new();
+        if self.tempo != Some(keyboard.tempo) {
+            self.tempo = Some(keyboard.tempo);
+            messages.push(OscMessage::new("/tempo", vec![OscArg::Int(keyboard.tempo as i32)]));
+        }
+
+        self.loops.resize(controller.get_loop_count(), None);
+        for (i, last) in self.loops.iter_mut().enumerate() {
+            let status = LoopStatus {
+                state   : controller.get_loop_state(i),
+                gain    : controller.get_loop_gain(i),
+                pan     : controller.get_loop_pan(i),
+                reverse : controller.is_loop_reversed(i),
+                speed   : controller.get_loop_speed(i)
+            };
+            // Messages are only built for the loops that have changed.
+            if *last == Some(status) {
+                continue
+            }
+            let prefix = format!("/loop/{}", i + 1);
+            if last.is_none_or(|l| l.state != status.state) {
+                messages.push(OscMessage::new(&format!("{}/state", prefix), vec![OscArg::Str(state_name(status.state).to_string())]));
+            }
+            if last.is_none_or(|l| l.gain != status.gain) {
+                messages.push(OscMessage::new(&format!("{}/gain", prefix), vec![OscArg::Int(status.gain as i32)]));
+            }
+            if last.is_none_or(|l| l.pan != status.pan) {
+                messages.push(OscMessage::new(&format!("{}/pan", prefix), vec![OscArg::Int(status.pan as i32)]));
+            }
+            if last.is_none_or(|l| l.reverse != status.reverse) {
+                messages.push(OscMessage::new(&format!("{}/reverse", prefix), vec![OscArg::Int(status.reverse as i32)]));
+            }
+            if last.is_none_or(|l| l.speed != status.speed) {
+                messages.push(OscMessage::new(&format!("{}/speed", prefix), vec![OscArg::Str(session::speed_name(status.speed).to_string())]));
+            }
+            *last = Some(status);
+        }
+
+        self.send(&messages);
+    }
+
+    pub fn notify_levels(&self, levels : &Levels, n_loops : usize) {
+        if self.clients.is_empty() {
+            return
+        }
+
+        let mut messages = vec![
+            level_message("/meter/input", &levels.input),
+            level_message("/meter/master", &levels.master)
+        ];
+        for (i, l) in levels.loops.iter().enumerate().take(n_loops) {
+            messages.push(level_message(&format!("/loop/{}/meter", i + 1), l));
+        }
+        self.send(&messages);
+    }
+
+    fn send(&self, messages : &[OscMessage]) {
+        for m in messages {
+            let bytes = m.to_bytes();
+            for c in &self.clients {
+                if let Err(e) = self.socket.send_to(&bytes, c) {
+                    eprintln!("Could not send OSC message to {}: {}", c, e);
+                }
+            }
+        }
+    }
+}
+
+fn level_message(address : &str, level : &Level) -> OscMessage {
+    OscMessage::new(address, vec![
+        OscArg::Float(amplitude_to_db(level.peak)),
+        OscArg::Float(amplitude_to_db(level.rms)),
+        OscArg::Int(level.clip as i32)
+    ])
+}
+
+fn set_effect_param(controller : &mut Controller, time : usize, target : usize, effect : &str, param : &str, value : Option<u8>) {
+    let effect = match Effect::from_name(effect) {
+        Some(e) => e,
+        None    => {
+            eprintln!("Unknown effect in OSC message: {}", effect);
+            return
+        }
+    };
+    match (effect.param_names().iter().position(|&p| p == param), value) {
+        (Some(k), Some(v)) => controller.set_effect_param(target, time, effect, k, v),
+        (None, _)          => eprintln!("Unknown parameter of the {} effect in OSC message: {}", effect.name(), param),
+        (_, None)          => eprintln!("Missing value of the {} {} in OSC message", effect.name(), param)
+    }
+}
+
+// Convert an OSC argument into a CC value.
+// Floating-point values up to 1.0 come from faders, other values are CC values.
+fn cc_value(arg : &OscArg) -> Option<u8> {
+    let v = match *arg {
+        OscArg::Int(n)               => n,
+        OscArg::Float(x) if x <= 1.0 => (x * 127.0).round() as i32,
+        OscArg::Float(x)             => x.round() as i32,
+        _                            => return None
+    };
+    Some(v.clamp(0, 127) as u8)
+}
+
+fn state_name(state : LoopState) -> &'static str {
+    match state {
+        LoopState::Empty     => "empty",
+        LoopState::Recording => "recording",
+        LoopState::Playing   => "playing",
+        LoopState::Muted     => "muted"
+    }
+}
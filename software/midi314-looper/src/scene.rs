@@ -0,0 +1,31 @@
+
+use midi314::LoopState;
+use control::{DEFAULT_GAIN, LOOP_COUNT};
+
+// The number of scenes that can be saved, selected by the value of the CC events.
+pub const SCENE_COUNT : usize = 16;
+
+// The Playing/Muted state and the gain of every loop.
+// The loops that were empty or recording when the scene was saved are not changed when it is recalled,
+// and neither are the loops that are empty or recording when it is recalled.
+#[derive(Clone, Copy)]
+pub struct Scene {
+    pub states : [Option<LoopState> ; LOOP_COUNT],
+    pub gains  : [u8 ; LOOP_COUNT]
+}
+
+impl Scene {
+    pub fn new(states : &[LoopState], gains : &[u8]) -> Self {
+        let mut scene = Self {
+            states : [None ; LOOP_COUNT],
+            gains  : [DEFAULT_GAIN ; LOOP_COUNT]
+        };
+        for (i, (&state, &gain)) in states.iter().zip(gains.iter()).enumerate().take(LOOP_COUNT) {
+            if state == LoopState::Playing || state == LoopState::Muted {
+                scene.states[i] = Some(state);
+            }
+            scene.gains[i] = gain;
+        }
+        scene
+    }
+}
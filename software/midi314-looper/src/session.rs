@@ -0,0 +1,237 @@
+
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use midi314::{Effect, Keyboard, LoopManager, LoopSpeed, EFFECTS, EFFECT_COUNT, EFFECT_PARAM_COUNT};
+use control::Controller;
+
+// The parameters of a loop that are kept in a session.
+#[derive(Clone, Copy)]
+pub struct LoopSettings {
+    pub gain    : u8,
+    pub pan     : u8,
+    pub reverse : bool,
+    pub speed   : LoopSpeed
+}
+
+// The settings of the looper, saved to a text file.
+// The recordings themselves are not part of the session.
+pub struct Session {
+    pub tempo : u32,
+    pub loops : Vec<LoopSettings>,
+    // The effect parameters of each loop, then of the master output.
+    pub effects : Vec<[[u8 ; EFFECT_PARAM_COUNT] ; EFFECT_COUNT]>
+}
+
+impl Session {
+    // Get the current settings from the control side.
+    pub fn capture(keyboard : &Keyboard, controller : &Controller) -> Self {
+        Self {
+            tempo : keyboard.tempo,
+            loops : (0 .. controller.get_loop_count()).map(|i| LoopSettings {
+                gain    : controller.get_loop_gain(i),
+                pan     : controller.get_loop_pan(i),
+                reverse : controller.is_loop_reversed(i),
+                speed   : controller.get_loop_speed(i)
+            }).collect(),
+            effects : (0 ..= controller.get_loop_count()).map(|t| {
+                let mut params = [[0 ; EFFECT_PARAM_COUNT] ; EFFECT_COUNT];
+                for (&effect, p) in EFFECTS.iter().zip(params.iter_mut()) {
+                    for (k, v) in p.iter_mut().enumerate() {
+                        *v = controller.get_effect_param(t, effect, k);
+                    }
+                }
+                params
+            }).collect()
+        }
+    }
+
+    // Send the settings of the session to the audio engine.
+    pub fn restore(&self, keyboard : &mut Keyboard, controller : &mut Controller, time : usize) {
+        keyboard.set_tempo(controller, time, self.tempo);
+        for (i, l) in self.loops.iter().enumerate().take(controller.get_loop_count()) {
+            controller.set_loop_gain(i, time, l.gain);
+            controller.set_loop_pan(i, time, l.pan);
+            controller.set_loop_reverse(i, time, l.reverse);
+            controller.set_loop_speed(i, time, l.speed);
+        }
+        for (t, params) in self.effects.iter().enumerate().take(controller.get_loop_count() + 1) {
+            for (&effect, p) in EFFECTS.iter().zip(params.iter()) {
+                for (k, &v) in p.iter().enumerate() {
+                    controller.set_effect_param(t, time, effect, k, v);
+                }
+            }
+        }
+    }
+
+    // Write the session to a text file, for instance:
+    //
+    //     tempo 120
+    //     loop 1 gain 127 pan 64 reverse 0 speed normal
+    //     effect 1 delay time 64 feedback 64 mix 0
+    //     effect master reverb size 64 damping 64 mix 0
+    //
+    // Loops are numbered from 1.
+    pub fn save(&self, file_name : &str) -> Result<(), String> {
+        let mut file = File::create(file_name).map_err(|e| e.to_string())?;
+        writeln!(file, "tempo {}", self.tempo).map_err(|e| e.to_string())?;
+        for (i, l) in self.loops.iter().enumerate() {
+            writeln!(file, "loop {} gain {} pan {} reverse {} speed {}",
+                     i + 1, l.gain, l.pan, l.reverse as u8, speed_name(l.speed)).map_err(|e| e.to_string())?;
+        }
+        for (t, params) in self.effects.iter().enumerate() {
+            let target = if t < self.loops.len() { (t + 1).to_string() } else { String::from("master") };
+            for (&effect, p) in EFFECTS.iter().zip(params.iter()) {
+                let names  = effect.param_names();
+                writeln!(file, "effect {} {} {} {} {} {} {} {}", target, effect.name(),
+                         names[0], p[0], names[1], p[1], names[2], p[2]).map_err(|e| e.to_string())?;
+            }
+        }
+        Ok(())
+    }
+
+    // Read a session from a text file.
+    // Empty lines and lines starting with # are ignored,
+    // and the settings that are missing keep their values from the given session.
+    pub fn load(file_name : &str, default : &Session) -> Result<Self, String> {
+        let file = File::open(file_name).map_err(|e| e.to_string())?;
+        let mut session = Session {
+            tempo   : default.tempo,
+            loops   : default.loops.clone(),
+            effects : default.effects.clone()
+        };
+        for (n, line) in BufReader::new(file).lines().enumerate() {
+            let line = line.map_err(|e| e.to_string())?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue
+            }
+
+            let fields : Vec<&str> = line.split_whitespace().collect();
+            let valid = match fields[0] {
+                "tempo" if fields.len() == 2 => fields[1].parse().map(|t| session.tempo = t).is_ok(),
+                "loop"  if fields.len().is_multiple_of(2) => match fields[1].parse::<usize>() {
+                    Ok(i) if i >= 1 && i <= session.loops.len() => {
+                        let l = &mut session.loops[i - 1];
+                        fields[2..].chunks(2).all(|kv| match kv[0] {
+                            "gain"    => kv[1].parse().map(|g| l.gain = g).is_ok(),
+                            "pan"     => kv[1].parse().map(|p| l.pan  = p).is_ok(),
+                            "reverse" => kv[1].parse::<u8>().map(|r| l.reverse = r != 0).is_ok(),
+                            "speed"   => parse_speed(kv[1]).map(|s| l.speed = s).is_some(),
+                            _         => false
+                        })
+                    },
+                    _ => false
+                },
+                "effect" if fields.len() >= 3 && fields.len() % 2 == 1 => {
+                    let target = match fields[1] {
+                        "master" => Some(session.loops.len()),
+                        n        => n.parse::<usize>().ok().filter(|&i| i >= 1 && i <= session.loops.len()).map(|i| i - 1)
+                    };
+                    match (target, Effect::from_name(fields[2])) {
+                        (Some(t), Some(effect)) if t < session.effects.len() => {
+                            let names  = effect.param_names();
+                            let params = &mut session.effects[t][effect as usize];
+                            fields[3..].chunks(2).all(|kv| match names.iter().position(|&n| n == kv[0]) {
+                                Some(k) => kv[1].parse().map(|v| params[k] = v).is_ok(),
+                                None    => false
+                            })
+                        },
+                        _ => false
+                    }
+                },
+                _ => false
+            };
+            if !valid {
+                return Err(format!("invalid setting at line {}", n + 1))
+            }
+        }
+        Ok(session)
+    }
+}
+
+pub fn speed_name(speed : LoopSpeed) -> &'static str {
+    match speed {
+        LoopSpeed::Normal => "normal",
+        LoopSpeed::Half   => "half",
+        LoopSpeed::Double => "double"
+    }
+}
+
+pub fn parse_speed(name : &str) -> Option<LoopSpeed> {
+    match name {
+        "normal" => Some(LoopSpeed::Normal),
+        "half"   => Some(LoopSpeed::Half),
+        "double" => Some(LoopSpeed::Double),
+        _        => None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, process};
+    use midi314::{Effect, LoopSpeed, DEFAULT_EFFECT_PARAMS, EFFECT_COUNT};
+    use super::{LoopSettings, Session};
+
+    fn file_name(test : &str) -> String {
+        ::std::env::temp_dir().join(format!("midi314-looper-{}-{}.txt", process::id(), test)).to_string_lossy().into_owned()
+    }
+
+    fn default_session(n_loops : usize) -> Session {
+        Session {
+            tempo   : 120,
+            loops   : vec![LoopSettings { gain : 127, pan : 64, reverse : false, speed : LoopSpeed::Normal } ; n_loops],
+            effects : vec![[DEFAULT_EFFECT_PARAMS ; EFFECT_COUNT] ; n_loops + 1]
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut session = default_session(3);
+        session.tempo = 97;
+        session.loops[0] = LoopSettings { gain : 90, pan : 0, reverse : true, speed : LoopSpeed::Half };
+        session.loops[2] = LoopSettings { gain : 0, pan : 127, reverse : false, speed : LoopSpeed::Double };
+        session.effects[1][Effect::Delay as usize] = [10, 20, 30];
+        session.effects[3][Effect::Reverb as usize] = [127, 0, 5];
+
+        let name = file_name("round-trip");
+        session.save(&name).unwrap();
+        let loaded = Session::load(&name, &default_session(3));
+        let _ = fs::remove_file(&name);
+        let loaded = loaded.unwrap();
+
+        assert_eq!(loaded.tempo, 97);
+        for (a, b) in loaded.loops.iter().zip(session.loops.iter()) {
+            assert_eq!((a.gain, a.pan, a.reverse), (b.gain, b.pan, b.reverse));
+            assert!(a.speed == b.speed);
+        }
+        assert!(loaded.effects == session.effects);
+    }
+
+    #[test]
+    fn partial_session() {
+        let name = file_name("partial");
+        fs::write(&name, "# Comment\n\nloop 2 pan 10\neffect master filter mix 99\n").unwrap();
+        let loaded = Session::load(&name, &default_session(2));
+        let _ = fs::remove_file(&name);
+        let loaded = loaded.unwrap();
+
+        // The missing settings keep their default values.
+        assert_eq!(loaded.tempo, 120);
+        assert_eq!((loaded.loops[0].pan, loaded.loops[1].pan, loaded.loops[1].gain), (64, 10, 127));
+        assert_eq!(loaded.effects[2][Effect::Filter as usize], [64, 64, 99]);
+        assert_eq!(loaded.effects[1][Effect::Filter as usize], DEFAULT_EFFECT_PARAMS);
+    }
+
+    #[test]
+    fn invalid_settings() {
+        let name = file_name("invalid");
+        for (contents, line) in &[("tempo 120\nloop 3 gain 1\n", 2), ("loop 1 gain\n", 1), ("effect 1 delay size 3\n", 1), ("speed half\n", 1)] {
+            fs::write(&name, contents).unwrap();
+            match Session::load(&name, &default_session(2)) {
+                Ok(_)  => panic!("{} was accepted", contents),
+                Err(e) => assert_eq!(e, format!("invalid setting at line {}", line))
+            }
+        }
+        let _ = fs::remove_file(&name);
+    }
+}
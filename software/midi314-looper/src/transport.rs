@@ -0,0 +1,150 @@
+
+use std::{mem, ptr};
+use std::os::raw::c_void;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use jack;
+use jack_sys as j;
+
+// The resolution of the bar/beat/tick position published to the other Jack clients.
+const TICKS_PER_BEAT : f64 = 1920.0;
+
+// The role of the looper with respect to the Jack transport.
+#[derive(Clone, Copy, PartialEq)]
+pub enum TransportMode {
+    // Publish the bar/beat/tick position from the tempo and the master loop.
+    Master,
+    // Start and stop the loops with the transport, and start the first loop on a bar.
+    Follower
+}
+
+// The state of the Jack transport at the beginning of a period.
+#[derive(Clone, Copy)]
+pub struct TransportPosition {
+    pub rolling : bool,
+    pub frame   : u32,
+    // The number of samples until the beginning of the next bar, when the position has bar information.
+    pub next_bar : Option<usize>
+}
+
+// Get the current state of the Jack transport.
+// This function can be called from the process callback.
+pub fn query(client : &jack::Client) -> TransportPosition {
+    let mut pos : j::jack_position_t = unsafe { mem::zeroed() };
+    let state = unsafe { j::jack_transport_query(client.raw(), &mut pos) };
+
+    let next_bar = if pos.valid & j::JackPositionBBT != 0 && pos.beats_per_minute > 0.0 && pos.ticks_per_beat > 0.0 {
+        let beat_length = pos.frame_rate as f64 * 60.0 / pos.beats_per_minute;
+        let bar_length  = (pos.beats_per_bar as f64 * beat_length).round() as usize;
+        let beats       = (pos.beat - 1) as f64 + pos.tick as f64 / pos.ticks_per_beat;
+        let remaining   = ((pos.beats_per_bar as f64 - beats) * beat_length).round() as usize;
+        Some(if remaining >= bar_length { 0 } else { remaining })
+    }
+    else {
+        None
+    };
+
+    TransportPosition {
+        rolling  : state == j::JackTransportRolling,
+        frame    : pos.frame,
+        next_bar : next_bar
+    }
+}
+
+// The timing information that the looper publishes as timebase master.
+// It is updated in the process callback and read in the timebase callback,
+// which are both called in the audio thread.
+pub struct Timebase {
+    beats_per_bar : usize,
+    tempo         : AtomicUsize,
+    master_length : AtomicUsize,
+    origin        : AtomicUsize,
+    turns         : AtomicUsize
+}
+
+impl Timebase {
+    pub fn new(tempo : u32, beats_per_bar : usize) -> Self {
+        Self {
+            beats_per_bar : beats_per_bar,
+            tempo         : AtomicUsize::new(tempo as usize),
+            master_length : AtomicUsize::new(0),
+            origin        : AtomicUsize::new(0),
+            turns         : AtomicUsize::new(0)
+        }
+    }
+
+    // Update the tempo and the position of the master loop.
+    // The origin is the transport frame at the beginning of the current turn of the master loop,
+    // and turns is the number of turns since the master loop was recorded.
+    pub fn update(&self, tempo : u32, master_length : usize, origin : u32, turns : usize) {
+        self.tempo.store(tempo as usize, Ordering::Relaxed);
+        self.master_length.store(master_length, Ordering::Relaxed);
+        self.origin.store(origin as usize, Ordering::Relaxed);
+        self.turns.store(turns, Ordering::Relaxed);
+    }
+
+    fn fill(&self, pos : &mut j::jack_position_t) {
+        let frame_rate    = pos.frame_rate as f64;
+        let beats_per_bar = self.beats_per_bar as f64;
+        let tempo         = self.tempo.load(Ordering::Relaxed) as f64;
+        let master_length = self.master_length.load(Ordering::Relaxed);
+
+        // Without a master loop, bars start at the beginning of the transport.
+        // With a master loop, its tempo is adjusted so that it contains a whole number of beats,
+        // and each turn of the master loop starts with a new bar.
+        let (bpm, bar, beat) = if master_length > 0 {
+            let length  = master_length as f64;
+            let beats   = loop_beats(master_length, tempo, frame_rate);
+            let bpm     = beats * 60.0 * frame_rate / length;
+            let elapsed = pos.frame.wrapping_sub(self.origin.load(Ordering::Relaxed) as u32) as i32 as i64;
+            let turns   = (self.turns.load(Ordering::Relaxed) as i64 + elapsed.div_euclid(master_length as i64)).max(0);
+            let beat    = elapsed.rem_euclid(master_length as i64) as f64 * bpm / (60.0 * frame_rate);
+            let bar     = (beat / beats_per_bar).floor();
+            let bars_per_turn = (beats / beats_per_bar).ceil();
+            (bpm, turns as f64 * bars_per_turn + bar, beat - bar * beats_per_bar)
+        }
+        else {
+            let beat = pos.frame as f64 * tempo / (60.0 * frame_rate);
+            let bar  = (beat / beats_per_bar).floor();
+            (tempo, bar, beat - bar * beats_per_bar)
+        };
+
+        pos.valid            = j::JackPositionBBT;
+        pos.bar              = bar as i32 + 1;
+        pos.beat             = beat.floor() as i32 + 1;
+        pos.tick             = (beat.fract() * TICKS_PER_BEAT) as i32;
+        pos.bar_start_tick   = bar * beats_per_bar * TICKS_PER_BEAT;
+        pos.beats_per_bar    = beats_per_bar as f32;
+        pos.beat_type        = 4.0;
+        pos.ticks_per_beat   = TICKS_PER_BEAT;
+        pos.beats_per_minute = bpm;
+    }
+}
+
+// The number of beats in a loop, rounded to a whole number.
+pub fn loop_beats(length : usize, tempo : f64, sample_rate : f64) -> f64 {
+    (length as f64 * tempo / (60.0 * sample_rate)).round().max(1.0)
+}
+
+unsafe extern "C" fn timebase_callback(_state : j::jack_transport_state_t, _n_frames : j::jack_nframes_t, pos : *mut j::jack_position_t, _new_pos : i32, arg : *mut c_void) {
+    let timebase = &*(arg as *const Timebase);
+    timebase.fill(&mut *pos);
+}
+
+// Register the looper as the timebase master.
+// The timebase must not be dropped before release_timebase is called.
+pub fn set_timebase(client : &jack::Client, timebase : &Timebase) -> bool {
+    unsafe {
+        j::jack_set_timebase_callback(client.raw(), 0, Some(timebase_callback), timebase as *const Timebase as *mut c_void) == 0
+    }
+}
+
+pub fn release_timebase(client : &jack::Client) {
+    unsafe {
+        j::jack_release_timebase(client.raw());
+    }
+}
+
+// Check whether the transport is rolling, from the control side.
+pub fn is_rolling(client : &jack::Client) -> bool {
+    unsafe { j::jack_transport_query(client.raw(), ptr::null_mut()) == j::JackTransportRolling }
+}
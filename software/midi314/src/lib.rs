@@ -3,6 +3,7 @@ extern crate rimd;
 #[macro_use] extern crate num_derive;
 extern crate num_traits;
 use num_traits::FromPrimitive;
+use std::cmp;
 
 #[derive(PartialEq, PartialOrd, Clone, Copy)]
 pub enum LoopState {
@@ -12,6 +13,77 @@ pub enum LoopState {
     Muted
 }
 
+// The state of the looper, from the first recording to the playback of the loops.
+#[derive(PartialEq, PartialOrd, Clone, Copy, FromPrimitive)]
+pub enum LooperState {
+    Idle,
+    WaitingFirstNote,
+    RecordingFirstLoop,
+    Running
+}
+
+// The effects that can be applied to a loop or to the master output.
+#[derive(PartialEq, Clone, Copy, FromPrimitive)]
+pub enum Effect {
+    Filter,
+    Delay,
+    Reverb,
+    Bitcrusher,
+    Tremolo
+}
+
+pub const EFFECT_COUNT : usize = 5;
+pub const EFFECTS : [Effect ; EFFECT_COUNT] = [Effect::Filter, Effect::Delay, Effect::Reverb, Effect::Bitcrusher, Effect::Tremolo];
+
+// Each effect has two parameters and a mix, as CC values.
+// A mix of 0 bypasses the effect.
+pub const EFFECT_PARAM_COUNT : usize = 3;
+pub const DEFAULT_EFFECT_PARAMS : [u8 ; EFFECT_PARAM_COUNT] = [64, 64, 0];
+
+impl Effect {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Effect::Filter     => "filter",
+            Effect::Delay      => "delay",
+            Effect::Reverb     => "reverb",
+            Effect::Bitcrusher => "bitcrusher",
+            Effect::Tremolo    => "tremolo"
+        }
+    }
+
+    pub fn param_names(&self) -> [&'static str ; EFFECT_PARAM_COUNT] {
+        match *self {
+            Effect::Filter     => ["cutoff", "resonance", "mix"],
+            Effect::Delay      => ["time", "feedback", "mix"],
+            Effect::Reverb     => ["size", "damping", "mix"],
+            Effect::Bitcrusher => ["bits", "downsample", "mix"],
+            Effect::Tremolo    => ["rate", "shape", "depth"]
+        }
+    }
+
+    pub fn from_name(name : &str) -> Option<Effect> {
+        EFFECTS.iter().cloned().find(|e| e.name() == name)
+    }
+}
+
+// When the input of the looper is passed through to its outputs.
+#[derive(PartialEq, Clone, Copy, FromPrimitive)]
+pub enum MonitorMode {
+    Always,
+    Never,
+    // While a loop is recording or waiting for the first note.
+    Recording,
+    // Before the first loop is recorded.
+    Idle
+}
+
+#[derive(PartialEq, Clone, Copy)]
+pub enum LoopSpeed {
+    Normal,
+    Half,
+    Double
+}
+
 pub trait LoopManager {
     fn get_loop_count(&self) -> usize;
     fn set_loop_state(&mut self, loop_index : usize, time : usize, state : LoopState);
@@ -55,11 +127,116 @@ pub trait LoopManager {
             }
         }
     }
+
+    // Restore the previous recording of a loop.
+    // Loop managers that do not keep the loop contents can ignore this event.
+    fn undo(&mut self, _loop_index : usize, _time : usize) {}
+
+    // Restore the recording that was replaced by the last undo.
+    fn redo(&mut self, _loop_index : usize, _time : usize) {}
+
+    // Set the gain of a loop, from 0 (silent) to 127 (unity gain).
+    fn set_loop_gain(&mut self, _loop_index : usize, _time : usize, _gain : u8) {}
+
+    fn get_loop_gain(&self, _loop_index : usize) -> u8 {
+        127
+    }
+
+    // Set the pan of a loop, from 0 (left) to 127 (right), 64 is the center.
+    fn set_loop_pan(&mut self, _loop_index : usize, _time : usize, _pan : u8) {}
+
+    fn get_loop_pan(&self, _loop_index : usize) -> u8 {
+        64
+    }
+
+    // Play a loop backwards or forwards.
+    fn set_loop_reverse(&mut self, _loop_index : usize, _time : usize, _reverse : bool) {}
+
+    fn is_loop_reversed(&self, _loop_index : usize) -> bool {
+        false
+    }
+
+    // Play a loop at half speed (an octave down) or double speed (an octave up).
+    fn set_loop_speed(&mut self, _loop_index : usize, _time : usize, _speed : LoopSpeed) {}
+
+    fn get_loop_speed(&self, _loop_index : usize) -> LoopSpeed {
+        LoopSpeed::Normal
+    }
+
+    fn toggle_reverse(&mut self, loop_index : usize, time : usize) {
+        let reverse = !self.is_loop_reversed(loop_index);
+        self.set_loop_reverse(loop_index, time, reverse);
+    }
+
+    fn toggle_speed(&mut self, loop_index : usize, time : usize, speed : LoopSpeed) {
+        // Selecting the current speed again restores the normal speed.
+        let speed = if self.get_loop_speed(loop_index) == speed {
+            LoopSpeed::Normal
+        }
+        else {
+            speed
+        };
+        self.set_loop_speed(loop_index, time, speed);
+    }
+
+    // Set the program of a loop that plays MIDI events.
+    fn set_loop_program(&mut self, _loop_index : usize, _time : usize, _program : u8) {}
+
+    // Change the tempo, in beats per minute.
+    fn set_tempo(&mut self, _time : usize, _tempo : u32) {}
+
+    // Save the Playing/Muted state and the gain of every loop into a scene.
+    fn save_scene(&mut self, _scene_index : usize, _time : usize) {}
+
+    // Restore the loop states and gains saved in a scene.
+    fn recall_scene(&mut self, _scene_index : usize, _time : usize) {}
+
+    // Set a parameter of an effect, from 0 to 127.
+    // The target is the index of a loop, or the number of loops for the master output.
+    fn set_effect_param(&mut self, _target : usize, _time : usize, _effect : Effect, _param : usize, _value : u8) {}
+
+    fn get_effect_param(&self, _target : usize, _effect : Effect, param : usize) -> u8 {
+        DEFAULT_EFFECT_PARAMS[param]
+    }
+
+    // Choose when the input of the looper is passed through to its outputs.
+    fn set_monitoring(&mut self, _time : usize, _mode : MonitorMode) {}
+
+    // Report the number of audio dropouts in the looper, up to 127.
+    fn set_xrun_count(&mut self, _time : usize, _count : u8) {}
+
+    // Report the number of periods where the master output of the looper exceeded full scale, up to 127.
+    fn set_clip_count(&mut self, _time : usize, _count : u8) {}
+
+    // Report the actual state of a loop, as published by the looper.
+    // Loop managers that follow the looper update their copy of the loop states here,
+    // instead of guessing them in set_loop_state.
+    fn loop_state_changed(&mut self, _loop_index : usize, _time : usize, _state : LoopState) {}
+
+    // Report the actual direction and speed of a loop, as published by the looper.
+    fn loop_mode_changed(&mut self, _loop_index : usize, _time : usize, _reverse : bool, _speed : LoopSpeed) {}
+
+    // Report the state of the looper.
+    fn looper_state_changed(&mut self, _time : usize, _state : LooperState) {}
+
+    // Report the position in the master loop, from 0 (beginning) to 127 (end).
+    fn set_loop_position(&mut self, _time : usize, _position : u8) {}
 }
 
-// Control Change events from 20 to 31 are undefined in the MIDI standard.
+// Control Change events 3, 9, 14, 15, from 20 to 31, from 52 to 63, from 85 to 90 and from 102 to 119
+// are undefined in the MIDI standard.
+// The effects are controlled with the General Purpose Controllers 16 to 19 and 80,
+// so that they can be assigned to the potentiometers.
 #[derive(PartialEq, Clone, Copy, FromPrimitive)]
 enum CustomCC {
+    LooperState   = 3,
+    LoopPosition  = 9,
+    LoopProgram   = 14,
+    SaveScene     = 15,
+    EffectParam1  = 16,
+    EffectParam2  = 17,
+    EffectMix     = 18,
+    EffectTarget  = 19,
     Record        = 20,
     Play          = 21,
     Mute          = 22,
@@ -68,9 +245,101 @@ enum CustomCC {
     All           = 25,
     SetMinPitch   = 26,
     SetMinProgram = 27,
-    Percussion    = 28
+    Percussion    = 28,
+    Undo          = 29,
+    Redo          = 30,
+    XrunCount     = 31,
+    ClipCount     = 61,
+    Monitoring    = 62,
+    EffectSelect  = 80,
+    Reverse       = 85,
+    HalfSpeed     = 86,
+    DoubleSpeed   = 87,
+    RecallScene   = 88,
+    TempoUp       = 89,
+    TempoDown     = 90
+}
+
+// The looper reports its number of xruns with this CC number.
+pub const XRUN_COUNT_CC : u8 = CustomCC::XrunCount as u8;
+
+// The looper reports the number of periods where its output exceeded full scale with this CC number.
+pub const CLIP_COUNT_CC : u8 = CustomCC::ClipCount as u8;
+
+// The gain and pan of each loop are set by CC numbers LOOP_GAIN_CC + index
+// and LOOP_PAN_CC + index, with the value in the data byte.
+pub const LOOP_GAIN_CC : u8 = 102;
+pub const LOOP_PAN_CC  : u8 = 111;
+pub const LOOP_CC_COUNT : u8 = LOOP_PAN_CC - LOOP_GAIN_CC;
+
+// The looper publishes the state of each loop with CC number LOOP_STATE_CC + index,
+// the state of the looper and the position in the master loop with these CC numbers.
+pub const LOOP_STATE_CC    : u8 = 52;
+pub const LOOPER_STATE_CC  : u8 = CustomCC::LooperState as u8;
+pub const LOOP_POSITION_CC : u8 = CustomCC::LoopPosition as u8;
+
+// The looper publishes the direction and speed of each loop with this CC number.
+// The data byte is the loop index times 8, plus 1 when the loop is reversed,
+// plus 2 at half speed or 4 at double speed.
+pub const LOOP_MODE_CC : u8 = 63;
+
+// Check whether a CC number is used to control the looper rather than the synthesizer.
+pub fn is_custom_cc(cc : u8) -> bool {
+    CustomCC::from_u8(cc).is_some() ||
+        (LOOP_GAIN_CC .. LOOP_GAIN_CC + 2 * LOOP_CC_COUNT).contains(&cc) ||
+        (LOOP_STATE_CC .. LOOP_STATE_CC + LOOP_CC_COUNT).contains(&cc) ||
+        cc == LOOP_MODE_CC
+}
+
+// The value of a loop state in the CC events published by the looper.
+pub fn loop_state_value(state : LoopState) -> u8 {
+    match state {
+        LoopState::Empty     => 0,
+        LoopState::Recording => 1,
+        LoopState::Playing   => 2,
+        LoopState::Muted     => 3
+    }
+}
+
+fn loop_state_from_value(n : u8) -> Option<LoopState> {
+    match n {
+        0 => Some(LoopState::Empty),
+        1 => Some(LoopState::Recording),
+        2 => Some(LoopState::Playing),
+        3 => Some(LoopState::Muted),
+        _ => None
+    }
+}
+
+// The value of the direction and speed of a loop in the CC events published by the looper.
+pub fn loop_mode_value(loop_index : usize, reverse : bool, speed : LoopSpeed) -> u8 {
+    let speed = match speed {
+        LoopSpeed::Normal => 0,
+        LoopSpeed::Half   => 2,
+        LoopSpeed::Double => 4
+    };
+    (loop_index as u8) * 8 + reverse as u8 + speed
 }
 
+fn loop_mode_from_value(n : u8) -> Option<(usize, bool, LoopSpeed)> {
+    let speed = match n & 6 {
+        0 => LoopSpeed::Normal,
+        2 => LoopSpeed::Half,
+        4 => LoopSpeed::Double,
+        _ => return None
+    };
+    Some(((n / 8) as usize, n & 1 != 0, speed))
+}
+
+// The raw bytes of a Control Change event on the default channel.
+pub fn control_change(cc : u8, n : u8) -> [u8 ; 3] {
+    [0xB0, cc, n]
+}
+
+// The range of the tempo, in beats per minute.
+pub const MIN_TEMPO : u32 = 30;
+pub const MAX_TEMPO : u32 = 300;
+
 pub struct Keyboard {
     pub min_pitch : u32,
     pub min_program : u32,
@@ -78,7 +347,11 @@ pub struct Keyboard {
     pub width_semitones : u32,
     pub program_keys : u32,
     pub tempo : u32,
-    pub percussion : bool
+    pub percussion : bool,
+    // The loop index, or the number of loops for the master output,
+    // and the effect whose parameters are set by the effect CCs.
+    pub effect_target : u32,
+    pub effect : Effect
 }
 
 impl Keyboard {
@@ -91,7 +364,9 @@ impl Keyboard {
             width_semitones : 28,
             program_keys    : 10,
             tempo           : 90,
-            percussion      : false
+            percussion      : false,
+            effect_target   : 0,
+            effect          : Effect::Filter
         }
     }
 
@@ -123,6 +398,11 @@ impl Keyboard {
         let index = n as usize;
         let mut result = true;
         match CustomCC::from_u8(cc) {
+            // Ignore the loop commands with an invalid loop index.
+            Some(CustomCC::Record) | Some(CustomCC::Play) | Some(CustomCC::Mute) | Some(CustomCC::Delete) |
+            Some(CustomCC::Solo) | Some(CustomCC::Undo) | Some(CustomCC::Redo) | Some(CustomCC::Reverse) |
+            Some(CustomCC::HalfSpeed) | Some(CustomCC::DoubleSpeed) | Some(CustomCC::LoopProgram)
+                if index >= lm.get_loop_count() => result = false,
             Some(CustomCC::Record)        => lm.set_loop_state(index, time, LoopState::Recording),
             Some(CustomCC::Play)          => lm.set_loop_state(index, time, LoopState::Playing),
             Some(CustomCC::Mute)          => lm.set_loop_state(index, time, LoopState::Muted),
@@ -132,8 +412,88 @@ impl Keyboard {
             Some(CustomCC::SetMinPitch)   => self.min_pitch   = n as u32,
             Some(CustomCC::SetMinProgram) => self.min_program = n as u32,
             Some(CustomCC::Percussion)    => self.percussion = n != 0,
-            _                             => result = false
+            Some(CustomCC::Undo)          => lm.undo(index, time),
+            Some(CustomCC::Redo)          => lm.redo(index, time),
+            Some(CustomCC::XrunCount)     => lm.set_xrun_count(time, n),
+            Some(CustomCC::ClipCount)     => lm.set_clip_count(time, n),
+            Some(CustomCC::Reverse)       => lm.toggle_reverse(index, time),
+            Some(CustomCC::HalfSpeed)     => lm.toggle_speed(index, time, LoopSpeed::Half),
+            Some(CustomCC::DoubleSpeed)   => lm.toggle_speed(index, time, LoopSpeed::Double),
+            Some(CustomCC::LoopProgram)   => lm.set_loop_program(index, time, self.current_program as u8),
+            Some(CustomCC::SaveScene)     => lm.save_scene(index, time),
+            Some(CustomCC::RecallScene)   => lm.recall_scene(index, time),
+            Some(CustomCC::Monitoring)    => match MonitorMode::from_u8(n) {
+                Some(mode) => lm.set_monitoring(time, mode),
+                None       => result = false
+            },
+            Some(CustomCC::EffectTarget)  => self.effect_target = n as u32,
+            Some(CustomCC::EffectSelect)  => match Effect::from_u8(n) {
+                Some(effect) => self.effect = effect,
+                None         => result = false
+            },
+            Some(CustomCC::EffectParam1)  => self.set_effect_param(lm, time, 0, n),
+            Some(CustomCC::EffectParam2)  => self.set_effect_param(lm, time, 1, n),
+            Some(CustomCC::EffectMix)     => self.set_effect_param(lm, time, 2, n),
+            Some(CustomCC::TempoUp)       => self.set_tempo(lm, time, self.tempo + n as u32),
+            Some(CustomCC::TempoDown)     => self.set_tempo(lm, time, self.tempo.saturating_sub(n as u32)),
+            Some(CustomCC::LooperState)   => match LooperState::from_u8(n) {
+                Some(state) => lm.looper_state_changed(time, state),
+                None        => result = false
+            },
+            Some(CustomCC::LoopPosition)  => {
+                // Position changes are not reported as updates, because they are too frequent
+                // to refresh a display.
+                lm.set_loop_position(time, n);
+                result = false
+            },
+            _                             => result = self.loop_control_change(lm, time, cc, n)
         }
         result
     }
+
+    pub fn set_tempo<T : LoopManager>(&mut self, lm : &mut T, time : usize, tempo : u32) {
+        self.tempo = tempo.clamp(MIN_TEMPO, MAX_TEMPO);
+        lm.set_tempo(time, self.tempo);
+    }
+
+    fn set_effect_param<T : LoopManager>(&self, lm : &mut T, time : usize, param : usize, n : u8) {
+        // All targets after the last loop select the master output.
+        let target = cmp::min(self.effect_target as usize, lm.get_loop_count());
+        lm.set_effect_param(target, time, self.effect, param, n);
+    }
+
+    fn loop_control_change<T : LoopManager>(&self, lm : &mut T, time : usize, cc : u8, n : u8) -> bool {
+        if (LOOP_GAIN_CC .. LOOP_GAIN_CC + LOOP_CC_COUNT).contains(&cc) {
+            let index = (cc - LOOP_GAIN_CC) as usize;
+            if index < lm.get_loop_count() {
+                lm.set_loop_gain(index, time, n);
+                return true
+            }
+        }
+        else if (LOOP_PAN_CC .. LOOP_PAN_CC + LOOP_CC_COUNT).contains(&cc) {
+            let index = (cc - LOOP_PAN_CC) as usize;
+            if index < lm.get_loop_count() {
+                lm.set_loop_pan(index, time, n);
+                return true
+            }
+        }
+        else if (LOOP_STATE_CC .. LOOP_STATE_CC + LOOP_CC_COUNT).contains(&cc) {
+            let index = (cc - LOOP_STATE_CC) as usize;
+            if index < lm.get_loop_count() {
+                if let Some(state) = loop_state_from_value(n) {
+                    lm.loop_state_changed(index, time, state);
+                    return true
+                }
+            }
+        }
+        else if cc == LOOP_MODE_CC {
+            if let Some((index, reverse, speed)) = loop_mode_from_value(n) {
+                if index < lm.get_loop_count() {
+                    lm.loop_mode_changed(index, time, reverse, speed);
+                    return true
+                }
+            }
+        }
+        false
+    }
 }